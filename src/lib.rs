@@ -2,10 +2,25 @@ pub mod parser;
 pub mod tokenizer;
 pub mod types;
 
+#[cfg(test)]
 mod tests {
     use crate::parser::*;
     use crate::tokenizer::*;
-    use std::env;
+    use crate::types::*;
+
+    // ソースを評価して変数の値を取り出す
+    fn get_var(input: &str, name: &str) -> Result<VarType, String> {
+        let tokenizer = Tokenizer::new();
+        let tokens = tokenizer.tokenize(input)?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        decoder.decode(&nodes)?;
+        scope_manager
+            .get_variable(name)
+            .ok_or_else(|| "Variable not found".to_string())
+    }
 
     #[test]
     fn test() -> Result<(), String> {
@@ -26,9 +41,1381 @@ let c = a+b+
         let mut scope_manager = ScopeManager::new();
         // パース
         let nodes = program(&mut parser)?;
-        let mut decoder = Decoder::new(&parser, &mut scope_manager);
+        let mut decoder = Decoder::new(&mut scope_manager);
         // 実行
+        // 末尾の演算子が不完全な式はエラーになる
+        assert!(decoder.decode(&nodes).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_numeric_builtins() -> Result<(), String> {
+        assert_eq!(get_var("let r = @abs(0 - 5)", "r")?, VarType::Int(5));
+        assert_eq!(get_var("let r = @abs(2.5)", "r")?, VarType::Float(2.5));
+        assert_eq!(get_var("l r = @sqrt(4)", "r")?, VarType::Float(2.0));
+        assert_eq!(get_var("l r = @sqrt(2.25)", "r")?, VarType::Float(1.5));
+        assert!(get_var("l r = @sqrt(0 - 1)", "r").is_err());
+        assert_eq!(get_var("let r = @min(3, 7)", "r")?, VarType::Int(3));
+        assert_eq!(get_var("let r = @max(3, 7)", "r")?, VarType::Int(7));
+        assert_eq!(get_var("let r = @max(3, 7.5)", "r")?, VarType::Float(7.5));
+        assert_eq!(get_var("let r = @min(3, 7.5)", "r")?, VarType::Float(3.0));
+        assert!(get_var("let r = @min(3)", "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_of() -> Result<(), String> {
+        assert_eq!(
+            get_var("let t = @type_of(3)", "t")?,
+            VarType::String("i32".to_string())
+        );
+        assert_eq!(
+            get_var("let t = @type_of(\"x\")", "t")?,
+            VarType::String("string".to_string())
+        );
+        assert_eq!(
+            get_var("let t = @type_of(1.5)", "t")?,
+            VarType::String("f64".to_string())
+        );
+        // 関数呼び出しの結果にも使える
+        assert_eq!(
+            get_var("let t = @type_of(@sqrt(4))", "t")?,
+            VarType::String("f64".to_string())
+        );
+        // 配列の要素にも使える
+        assert_eq!(
+            get_var(
+                "let a = [1, \"x\"] let t = @type_of(a[0]) + @type_of(a)",
+                "t"
+            )?,
+            VarType::String("i32array".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_endings_and_bom() -> Result<(), String> {
+        let tokenizer = Tokenizer::new();
+        let lf = tokenizer.tokenize("let a = 1\nlet b = a + 2\n")?;
+        let crlf = tokenizer.tokenize("let a = 1\r\nlet b = a + 2\r\n")?;
+        let bom = tokenizer.tokenize("\u{feff}let a = 1\nlet b = a + 2\n")?;
+        assert_eq!(lf, crlf);
+        assert_eq!(lf, bom);
+        assert_eq!(
+            get_var("\u{feff}let a = 1\r\nlet b = a + 2\r\n", "b")?,
+            VarType::Int(3)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_to_number() -> Result<(), String> {
+        assert_eq!(get_var("let n = @to_int(\"42\")", "n")?, VarType::Int(42));
+        assert_eq!(
+            get_var("let n = @to_float(\"1.25\")", "n")?,
+            VarType::Float(1.25)
+        );
+        assert_eq!(
+            get_var("let n = @to_float(\"3\")", "n")?,
+            VarType::Float(3.0)
+        );
+        let err = get_var("let n = @to_int(\"abc\")", "n").unwrap_err();
+        assert!(err.contains("abc"));
+        assert!(get_var("let n = @to_float(\"1.2.3\")", "n").is_err());
+        for input in ["NaN", "inf", "-infinity", "1e400"] {
+            let err = get_var(&format!("let n = @to_float(\"{}\")", input), "n").unwrap_err();
+            assert!(
+                err.starts_with(&format!("to_float: {:?} is not a finite number", input)),
+                "{}",
+                err
+            );
+        }
+        assert!(get_var("let n = @to_int(1)", "n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_builtins() -> Result<(), String> {
+        let string = |s: &str| VarType::String(s.to_string());
+        assert_eq!(
+            get_var("let s = @to_upper(\"straße\")", "s")?,
+            string("STRASSE")
+        );
+        assert_eq!(get_var("let s = @to_lower(\"ÄÖÜ\")", "s")?, string("äöü"));
+        assert_eq!(
+            get_var("let s = @trim(\"  こんにちは  \")", "s")?,
+            string("こんにちは")
+        );
+        assert_eq!(get_var("let s = @trim(\"\")", "s")?, string(""));
+        assert_eq!(
+            get_var("let s = @split(\"a,b,c\", \",\")", "s")?,
+            VarType::Array(vec![string("a"), string("b"), string("c")])
+        );
+        assert_eq!(
+            get_var("let s = @split(\"日本\", \"\")", "s")?,
+            VarType::Array(vec![string("日"), string("本")])
+        );
+        assert_eq!(
+            get_var("let s = @split(\"\", \",\")", "s")?,
+            VarType::Array(vec![string("")])
+        );
+        assert!(get_var("let s = @to_upper(1)", "s").is_err());
+        assert!(get_var("let s = @split(\"a\", 1)", "s").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_op() -> Result<(), String> {
+        let cases = [
+            ("+", VarType::Int(2), VarType::Int(3), "2 + 3"),
+            ("-", VarType::Int(2), VarType::Int(3), "2 - 3"),
+            ("*", VarType::Float(1.5), VarType::Float(2.0), "1.5 * 2.0"),
+            ("/", VarType::Int(7), VarType::Int(2), "7 / 2"),
+        ];
+        for (op, a, b, src) in cases {
+            let expected = get_var(&format!("let x = {}", src), "x")?;
+            assert_eq!(Decoder::apply_op(op, &a, &b)?, expected);
+        }
+        assert!(
+            Decoder::apply_op("+", &VarType::Int(1), &VarType::String("a".to_string())).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_trailing_semicolon() -> Result<(), String> {
+        // 最後の文に;がなければブロックの値になる
+        assert_eq!(
+            get_var("let a = { let x = 2; x * 3 }", "a")?,
+            VarType::Int(6)
+        );
+        assert_eq!(
+            get_var("let a = { let x = 2; x * 3; }", "a")?,
+            VarType::Null
+        );
+        // {}は空のブロックではなく空のマップ
+        assert_eq!(
+            get_var("let a = {}", "a")?,
+            VarType::Object(Default::default())
+        );
+        assert_eq!(get_var("let a = 1; let b = a + 1;", "b")?, VarType::Int(2));
+        assert_eq!(get_var("let a = 1; let b = a + 1", "b")?, VarType::Int(2));
+        assert!(get_var("let a = { 1", "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_variables() -> Result<(), String> {
+        let tokens = Tokenizer::new().tokenize("let a = undefined_name")?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        // 既定では未定義の変数の読み込みはエラー
+        let mut scope_manager = ScopeManager::new();
+        assert!(Decoder::new(&mut scope_manager).decode(&nodes).is_err());
+        // 緩いモードではNullになる
+        let mut scope_manager = ScopeManager::new();
+        Decoder::new(&mut scope_manager)
+            .strict_variables(false)
+            .decode(&nodes)?;
+        assert_eq!(scope_manager.get_variable("a"), Some(VarType::Null));
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjacent_string_literals() -> Result<(), String> {
+        assert_eq!(
+            get_var("let s = \"foo\" \"bar\"", "s")?,
+            VarType::String("foobar".to_string())
+        );
+        assert_eq!(
+            get_var("let s = \"a\"\n    \"b\"\n    \"c\"", "s")?,
+            VarType::String("abc".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scientific_notation() -> Result<(), String> {
+        assert_eq!(get_var("let f = 1.5e3", "f")?, VarType::Float(1500.0));
+        assert_eq!(get_var("let f = 2e2", "f")?, VarType::Float(200.0));
+        assert_eq!(get_var("let f = 2E+2", "f")?, VarType::Float(200.0));
+        assert_eq!(get_var("let f = 25e-1", "f")?, VarType::Float(2.5));
+        assert_eq!(get_var("let f = 2e10", "f")?, VarType::Float(2e10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_char_literal() -> Result<(), String> {
+        let string = |s: &str| VarType::String(s.to_string());
+        assert_eq!(get_var("let c = 'A'", "c")?, string("A"));
+        assert_eq!(get_var("let c = '\\n'", "c")?, string("\n"));
+        assert_eq!(get_var("let c = '\\t'", "c")?, string("\t"));
+        assert_eq!(get_var("let c = '\\''", "c")?, string("'"));
+        assert_eq!(get_var("let c = 'あ'", "c")?, string("あ"));
+        let tokens = Tokenizer::new().tokenize("'A'")?;
+        assert_eq!(tokens[0].token_type, TokenType::Char);
+        assert!(Tokenizer::new().tokenize("'AB'").is_err());
+        assert!(Tokenizer::new().tokenize("''").is_err());
+        assert!(Tokenizer::new().tokenize("'\\q'").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_escapes() -> Result<(), String> {
+        let string = |s: &str| VarType::String(s.to_string());
+        assert_eq!(
+            get_var(r#"let s = "line1\nline2""#, "s")?,
+            string("line1\nline2")
+        );
+        assert_eq!(
+            get_var(r#"let s = "a\tb\r\\\"\0""#, "s")?,
+            string("a\tb\r\\\"\0")
+        );
+        assert_eq!(
+            get_var(r#"let s = "\u{3042}\u{1F600}""#, "s")?,
+            string("あ😀")
+        );
+        let err = Tokenizer::new()
+            .tokenize("let a = 1\nlet s = \"ab\\q\"")
+            .unwrap_err();
+        assert!(err.contains("2行 12列"), "{}", err);
+        assert!(Tokenizer::new().tokenize(r#""\u{110000}""#).is_err());
+        assert!(Tokenizer::new().tokenize(r#""\u{41""#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_interpolation() -> Result<(), String> {
+        let string = |s: &str| VarType::String(s.to_string());
+        assert_eq!(
+            get_var(r#"let x = 2 let s = "x is ${x}""#, "s")?,
+            string("x is 2")
+        );
+        assert_eq!(
+            get_var(r#"let x = 2 let s = "x + 1 is ${x + 1}!""#, "s")?,
+            string("x + 1 is 3!")
+        );
+        assert_eq!(
+            get_var(r#"let s = "${@to_upper("a")}${1.5}""#, "s")?,
+            string("A1.5")
+        );
+        // ${}の中の文字列や文字の}や{は数えない
+        assert_eq!(get_var(r#"let s = "${"}"}""#, "s")?, string("}"));
+        assert_eq!(get_var(r#"let s = "${"{"}!""#, "s")?, string("{!"));
+        assert_eq!(get_var(r#"let s = "${'}'}${"\"}"}""#, "s")?, string("}\"}"));
+        assert_eq!(get_var(r#"let s = "${"<${"}"}>"}""#, "s")?, string("<}>"));
+        // strという名前の変数があっても${}は文字列にする
+        assert_eq!(
+            get_var(r#"let str = fn(v) => 0 let s = "v=${1}""#, "s")?,
+            string("v=1")
+        );
+        // \${ はそのまま
+        assert_eq!(get_var(r#"let s = "\${x}""#, "s")?, string("${x}"));
+        assert!(get_var(r#"let s = "${1 +}""#, "s").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_match() -> Result<(), String> {
+        let string = |s: &str| VarType::String(s.to_string());
+        let src = r#"
+let x = 2
+let s = match x {
+    1 => "one",
+    1 + 1 => "two",
+    _ => "many",
+}
+"#;
+        assert_eq!(get_var(src, "s")?, string("two"));
+        assert_eq!(
+            get_var(r#"let s = match "b" { "a" => 1, "b" => { 2 } }"#, "s")?,
+            VarType::Int(2)
+        );
+        assert_eq!(
+            get_var(r#"let s = match 9 { 1 => "one", _ => "many" }"#, "s")?,
+            string("many")
+        );
+        // どの腕にも一致しなければNull
+        assert_eq!(get_var("let s = match 9 { 1 => 1 }", "s")?, VarType::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn test_globals_json() -> Result<(), String> {
+        let tokens = Tokenizer::new().tokenize(
+            "let b = \"hi \\\"x\\\"\" let a = 1 + 2 let c = { let d = 1.5; d } let f = fn(x) => x",
+        )?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        decoder.decode(&nodes)?;
+        // ブロック内の変数と関数は含まれない
+        assert_eq!(decoder.globals_json(), r#"{"a":3,"b":"hi \"x\"","c":1.5}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_source_context() -> Result<(), String> {
+        let src = "let a = 1\nlet b = a + @sqrt(\"x\")\n";
+        let tokens = Tokenizer::new().tokenize(src)?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        let mut scope_manager = ScopeManager::new();
+        let err = Decoder::new(&mut scope_manager)
+            .source(src)
+            .decode(&nodes)
+            .unwrap_err();
+        assert!(err.contains("line 2, column 13"), "{}", err);
+        assert!(
+            err.ends_with("let b = a + @sqrt(\"x\")\n            ^"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_operator_precedence() -> Result<(), String> {
+        // -a ** 2 は -(a ** 2)、b++ は更新前の値
+        let src = "let a = 3 let b = 1 let r = -a ** 2 + b++";
+        assert_eq!(get_var(src, "r")?, VarType::Int(-8));
+        assert_eq!(get_var(src, "b")?, VarType::Int(2));
+        assert_eq!(get_var("let r = 2 ** 3 ** 2", "r")?, VarType::Int(512));
+        assert_eq!(get_var("let r = 10 - 4 - 3", "r")?, VarType::Int(3));
+        assert_eq!(get_var("let r = -2 * -3", "r")?, VarType::Int(6));
+        assert_eq!(get_var("let r = 2 ** -1", "r")?, VarType::Float(0.5));
+        assert_eq!(get_var("let a = 5 let r = a-- - a", "r")?, VarType::Int(1));
+        assert_eq!(
+            get_var("let r = 1 + 2 * 3 == 7 && !false", "r")?,
+            VarType::Bool(true)
+        );
+        assert_eq!(
+            get_var("let r = 1 > 2 || 2 <= 2 && \"a\" != \"b\"", "r")?,
+            VarType::Bool(true)
+        );
+        // 短絡評価
+        assert_eq!(
+            get_var("let r = false && undefined", "r")?,
+            VarType::Bool(false)
+        );
+        assert!(get_var("let r = 1++", "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_int_division() -> Result<(), String> {
+        let tokenizer = Tokenizer::new();
+        let tokens = tokenizer.tokenize("let t = 5 / 2 let f = 5 / 2")?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        let (truncated, divided) = (&nodes[..1], &nodes[1..]);
+        let mut scope_manager = ScopeManager::new();
+        Decoder::new(&mut scope_manager).decode(truncated)?;
+        Decoder::new(&mut scope_manager)
+            .int_division(false)
+            .decode(divided)?;
+        assert_eq!(scope_manager.get_variable("t"), Some(VarType::Int(2)));
+        assert_eq!(scope_manager.get_variable("f"), Some(VarType::Float(2.5)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_finite_floats() -> Result<(), String> {
+        assert!(get_var("let r = 0.0 / 0.0", "r").is_err());
+        assert!(get_var("let r = 1.0 / 0.0", "r").is_err());
+        assert!(get_var("let r = 1e308 * 10.0", "r").is_err());
+        assert!(get_var("let r = 1e400", "r").is_err());
+        assert!(get_var("let r = 10.0 ** 400.0", "r").is_err());
+        // エラーには位置が付く
+        let tokens = Tokenizer::new().tokenize("let r = 1.0 +\n  1e308 * 10.0")?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        let mut scope_manager = ScopeManager::new();
+        let err = Decoder::new(&mut scope_manager).decode(&nodes).unwrap_err();
+        assert!(err.contains("not a finite number"), "{}", err);
+        assert!(err.contains("(line 2, column 9)"), "{}", err);
+        assert_eq!(
+            Decoder::apply_op("<", &VarType::Float(1.0), &VarType::Float(2.0)),
+            Ok(VarType::Bool(true))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_const() -> Result<(), String> {
+        assert_eq!(get_var("let x = 1 x = x + 1", "x")?, VarType::Int(2));
+        assert_eq!(get_var("const x = 1 let y = x * 2", "y")?, VarType::Int(2));
+        let err = get_var("const x = 1 x = 2", "x").unwrap_err();
+        assert!(err.starts_with("cannot assign to constant: x"), "{}", err);
+        let err = get_var("const x = 1 x++", "x").unwrap_err();
+        assert!(err.starts_with("cannot assign to constant: x"), "{}", err);
+        assert!(get_var("y = 1", "y").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shadowing() -> Result<(), String> {
+        // 内側のブロックの宣言は外側の変数を隠すだけ
+        let src = "let x = 1 let y = { let x = 2; x } let z = x";
+        assert_eq!(get_var(src, "y")?, VarType::Int(2));
+        assert_eq!(get_var(src, "z")?, VarType::Int(1));
+        // 同じスコープでの宣言し直しは上書き
+        assert_eq!(get_var("let x = 1 let x = x + 1", "x")?, VarType::Int(2));
+        // 代入は外側の変数を書き換える
+        assert_eq!(
+            get_var("let x = 1 { x = 5; } let z = x", "z")?,
+            VarType::Int(5)
+        );
+        // 内側で定数を隠しても外側への代入はエラー
+        assert!(get_var("const x = 1 { let x = 2; x = 3; } x = 4", "x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_scope() -> Result<(), String> {
+        // ブロックの中で宣言した変数はブロックの外からは見えない
+        assert!(get_var("{ let inner = 1; } let r = inner", "r").is_err());
+        assert!(get_var("let a = { let inner = 1; inner } ", "inner").is_err());
+        assert_eq!(
+            get_var("let r = { let a = 1; { let b = a + 1; a + b } }", "r")?,
+            VarType::Int(3)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_conversion() -> Result<(), String> {
+        let tokens = Tokenizer::new().tokenize("let first = { xs } let label = name + \"!\"")?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        decoder.set_global("xs", vec![1i64, 2, 3]);
+        decoder.set_global("name", "bob");
+        decoder.decode(&nodes)?;
+        assert_eq!(decoder.get_global::<Vec<i64>>("first")?, vec![1, 2, 3]);
+        assert_eq!(decoder.get_global::<String>("label")?, "bob!");
+        assert!(decoder.get_global::<i64>("label").is_err());
+        assert_eq!(f64::try_from(VarType::from(2i64))?, 2.0);
+        assert_eq!(
+            VarType::from(vec![true]),
+            VarType::Array(vec![VarType::Bool(true)])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_constants() -> Result<(), String> {
+        let src = "let rate = 3 let n = 2 n = n + 1 let r = { let t = rate * n; t + rate }";
+        let tokens = Tokenizer::new().tokenize(src)?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        let optimized = propagate_constants(&nodes);
+        // rateの読み込みだけが埋め込まれ、書き換えられるnとブロック内のtは残る
+        let var_reads = |nodes: &[Box<Node>]| format!("{:?}", nodes).matches("Var(").count();
+        assert_eq!(var_reads(&nodes), 5);
+        assert_eq!(var_reads(&optimized), 3);
+        let mut plain = ScopeManager::new();
+        Decoder::new(&mut plain).decode(&nodes)?;
+        let mut fast = ScopeManager::new();
+        Decoder::new(&mut fast).optimize(true).decode(&nodes)?;
+        assert_eq!(plain.get_variable("r"), Some(VarType::Int(12)));
+        assert_eq!(fast.get_variable("r"), plain.get_variable("r"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_line() -> Result<(), String> {
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        assert_eq!(decoder.eval_line("l x = 1;")?, (VarType::Null, true));
+        assert_eq!(decoder.eval_line("x + 1")?, (VarType::Int(2), false));
+        assert_eq!(decoder.eval_line("x = 5")?, (VarType::Null, true));
+        assert_eq!(decoder.eval_line("x")?, (VarType::Int(5), false));
+        assert_eq!(decoder.eval_line("")?, (VarType::Null, true));
+        let err = decoder.eval_line("x + y").unwrap_err();
+        assert!(err.ends_with("x + y\n    ^"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_division_by_zero() -> Result<(), String> {
+        assert_eq!(get_var("let r = 7 % 3 + 10 / 4", "r")?, VarType::Int(3));
+        assert_eq!(get_var("let r = 7.5 % 2.0", "r")?, VarType::Float(1.5));
+        for src in [
+            "let r = 5 / 0",
+            "let r = 5 % 0",
+            "let r = 5.0 / 0.0",
+            "let r = 5.0 % 0.0",
+        ] {
+            let err = get_var(src, "r").unwrap_err();
+            assert!(err.starts_with("Division by zero"), "{}", err);
+        }
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager).int_division(false);
+        assert!(decoder
+            .eval_line("5 / 0")
+            .unwrap_err()
+            .starts_with("Division by zero"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_map() -> Result<(), String> {
+        let src = r#"let m = { "b": 2, "a": 1 + 1, } m["a"] = 3 m["c"] = "x"
+            let a = m["a"] let missing = m["z"] let k = @keys(m) let v = @values(m)"#;
+        assert_eq!(get_var(src, "a")?, VarType::Int(3));
+        assert_eq!(get_var(src, "missing")?, VarType::Null);
+        assert_eq!(get_var(src, "k")?, VarType::from(vec!["a", "b", "c"]));
+        assert_eq!(
+            get_var(src, "v")?,
+            VarType::Array(vec![
+                VarType::Int(3),
+                VarType::Int(2),
+                VarType::String("x".to_string())
+            ])
+        );
+        assert_eq!(
+            get_var(r#"let s = @str({ "a": 1, "b": "x" })"#, "s")?,
+            VarType::String("{a: 1, b: x}".to_string())
+        );
+        // { "a" }はブロックのまま、{}は空のマップ
+        assert_eq!(
+            get_var("let e = {}", "e")?,
+            VarType::Object(Default::default())
+        );
+        // 文や関数の本体、matchの腕の{}は今まで通り空のブロック
+        assert_eq!(
+            get_var("let f = fn() => {} let r = @f()", "r")?,
+            VarType::Null
+        );
+        assert_eq!(
+            get_var("let r = match 1 { 1 => {}, _ => 2 }", "r")?,
+            VarType::Null
+        );
+        let mut scope_manager = ScopeManager::new();
+        assert_eq!(
+            Decoder::new(&mut scope_manager).eval_line("{}")?,
+            (VarType::Null, false)
+        );
+        let tokens = Tokenizer::new().tokenize("let e = {}")?;
+        assert_eq!(
+            program(&mut Parser::new(&tokens))?[0].to_source(),
+            "let e = {}"
+        );
+        assert_eq!(
+            get_var(
+                r#"let e = {} e["k"] = 1 let s = @str(e) + @type_of({})"#,
+                "s"
+            )?,
+            VarType::String("{k: 1}object".to_string())
+        );
+        assert_eq!(
+            get_var(r#"let b = { "a" }"#, "b")?,
+            VarType::String("a".to_string())
+        );
+        assert!(get_var(r#"const m = { "a": 1 } m["a"] = 2"#, "m").is_err());
+        assert!(get_var("let n = 1 let r = n[\"a\"]", "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tuple() -> Result<(), String> {
+        let src = r#"l t = (1, "x", true); let a = t.0 let b = t.1 let c = t.2"#;
+        assert_eq!(get_var(src, "a")?, VarType::Int(1));
+        assert_eq!(get_var(src, "b")?, VarType::String("x".to_string()));
+        assert_eq!(get_var(src, "c")?, VarType::Bool(true));
+        assert_eq!(
+            get_var("let r = ((1, 2), (3,)).0.1 + (4) * 2", "r")?,
+            VarType::Int(10)
+        );
+        assert_eq!(get_var("let t = ()", "t")?, VarType::Tuple(vec![]));
+        assert_eq!(
+            get_var(r#"let s = @str((1, "a")) + @type_of((1,))"#, "s")?,
+            VarType::String("(1, a)tuple".to_string())
+        );
+        let err = get_var("let t = (1, 2) let r = t.2", "r").unwrap_err();
+        assert!(err.contains("out of range"), "{}", err);
+        assert!(get_var("let n = 1 let r = n.0", "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_logical_keywords() -> Result<(), String> {
+        assert_eq!(
+            get_var("let r = 1 < 2 and not false || false", "r")?,
+            VarType::Bool(true)
+        );
+        assert_eq!(
+            get_var("let r = not (true && false) and (false or !false)", "r")?,
+            VarType::Bool(true)
+        );
+        assert_eq!(
+            get_var("let r = false or false && true", "r")?,
+            VarType::Bool(false)
+        );
+        // キーワードは変数名にできない
+        assert!(get_var("let and = 1", "and").is_err());
+        assert_eq!(get_var("let android = 1", "android")?, VarType::Int(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_str() -> Result<(), String> {
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        assert_eq!(decoder.eval_str("2 + 3")?, VarType::Int(5));
+        assert_eq!(decoder.eval_str("let a = 4; a * a")?, VarType::Int(16));
+        assert!(decoder.eval_str("a +").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_line_numbers() -> Result<(), String> {
+        let src = "let a = 1\nlet b = {\n  let c = a;\n  c + missing\n}\nlet d = 2";
+        let tokens = Tokenizer::new().tokenize(src)?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        let mut scope_manager = ScopeManager::new();
+        let err = Decoder::new(&mut scope_manager).decode(&nodes).unwrap_err();
+        assert!(
+            err.starts_with("Variable not found: missing (line 4, column 7)"),
+            "{}",
+            err
+        );
+        // 前の文のエラー位置は引き継がない
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        assert!(decoder.eval_line("let x = 1 / 0").is_err());
+        let err = decoder.eval_line("\n\nlet y = z").unwrap_err();
+        assert!(err.contains("(line 3, column 9)"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_all() -> Result<(), String> {
+        let tokens =
+            Tokenizer::new().tokenize("let a = 1 +;\nlet b = 2;\nlet = 3;\nlet c = (1;")?;
+        let mut parser = Parser::new(&tokens);
+        let (nodes, errors) = program_all(&mut parser);
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].ends_with("(line 1, column 12)"), "{}", errors[0]);
+        assert!(errors[1].ends_with("(line 3, column 5)"), "{}", errors[1]);
+        assert_eq!(nodes.len(), 1);
+        let mut scope_manager = ScopeManager::new();
+        Decoder::new(&mut scope_manager).decode(&nodes)?;
+        assert_eq!(scope_manager.get_variable("b"), Some(VarType::Int(2)));
+        // エラーがなければprogramと同じ
+        let tokens = Tokenizer::new().tokenize("let a = 1 let b = a")?;
+        let (nodes, errors) = program_all(&mut Parser::new(&tokens));
+        assert!(errors.is_empty());
+        assert_eq!(nodes, program(&mut Parser::new(&tokens))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_array_index() -> Result<(), String> {
+        let src = "let xs = [10, 20, 30,] xs[1] = xs[0] + xs[2] let a = xs[1] let b = [[1], [2, 3]][1][0]";
+        assert_eq!(get_var(src, "a")?, VarType::Int(40));
+        assert_eq!(get_var(src, "b")?, VarType::Int(2));
+        assert_eq!(
+            get_var(r#"let w = @split("a b", " ")[1]"#, "w")?,
+            VarType::String("b".to_string())
+        );
+        assert_eq!(get_var("let e = []", "e")?, VarType::Array(vec![]));
+        for src in ["let r = [1, 2][2]", "let xs = [1] xs[5] = 0"] {
+            let err = get_var(src, "r").unwrap_err();
+            assert!(err.starts_with("index out of bounds"), "{}", err);
+        }
+        assert!(get_var("let r = 5[0]", "r").is_err());
+        assert!(get_var(r#"let r = [1]["a"]"#, "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_negative_index() -> Result<(), String> {
+        let src = "let xs = [1, 2, 3] let last = xs[-1] xs[-3] = 9 let first = xs[0]";
+        assert_eq!(get_var(src, "last")?, VarType::Int(3));
+        assert_eq!(get_var(src, "first")?, VarType::Int(9));
+        let err = get_var("let r = [1, 2, 3][-4]", "r").unwrap_err();
+        assert!(err.starts_with("index out of bounds: -4"), "{}", err);
+        assert!(get_var("let xs = [1] xs[-2] = 0", "xs").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice() -> Result<(), String> {
+        let slice = |src: &str| get_var(&format!("let xs = [1, 2, 3, 4] let r = {}", src), "r");
+        assert_eq!(slice("@slice(xs, 1, 3)")?, VarType::from(vec![2i64, 3]));
+        assert_eq!(slice("@slice(xs, 2, 100)")?, VarType::from(vec![3i64, 4]));
+        assert_eq!(slice("@slice(xs, -5, 1)")?, VarType::from(vec![1i64]));
+        assert_eq!(slice("@slice(xs, 9, 10)")?, VarType::Array(vec![]));
+        assert!(slice("@slice(xs, 3, 1)").is_err());
+        assert!(slice("@slice(\"abc\", 0, 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_print() -> Result<(), String> {
+        let print = |src: &str| get_var(&format!("let a = 1 let r = {}", src), "r");
+        assert_eq!(print(r#"@print("no placeholders {{}}")"#)?, VarType::Null);
+        assert_eq!(print(r#"@print("a = {}", a)"#)?, VarType::Null);
+        assert_eq!(
+            print(r#"@print("{} and {:?} and {}", a, "s", [a])"#)?,
+            VarType::Null
+        );
+        let err = print(r#"@print("{} and {}", a)"#).unwrap_err();
+        assert!(err.contains("more placeholders than arguments"), "{}", err);
+        assert!(print(r#"@print("{x}", a)"#).is_err());
+        assert!(print("@print()").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format() -> Result<(), String> {
+        assert_eq!(
+            get_var(
+                r#"let n = 3 let r = @format("{} items: {:?} {{ok}}", n, ["a", 1.5])"#,
+                "r"
+            )?,
+            VarType::String(r#"3 items: ["a",1.5] {ok}"#.to_string())
+        );
+        assert_eq!(
+            get_var(r#"let r = @format("plain")"#, "r")?,
+            VarType::String("plain".to_string())
+        );
+        assert!(get_var(r#"let r = @format("{}")"#, "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_builtins() -> Result<(), String> {
+        let src = r#"let v = { "n": [1, 2.5, true], "s": "a\"b", "e": {}, "z": [] }
+            let j = @to_json(v) let back = @parse_json(j)"#;
+        assert_eq!(
+            get_var(src, "j")?,
+            VarType::String(
+                "{\n  \"e\": {},\n  \"n\": [\n    1,\n    2.5,\n    true\n  ],\n  \"s\": \"a\\\"b\",\n  \"z\": []\n}"
+                    .to_string()
+            )
+        );
+        assert_eq!(get_var(src, "back")?, get_var(src, "v")?);
+        assert_eq!(
+            get_var(
+                r#"let r = @parse_json(" [null, -3, 1e2, \"\\u00e9\\ud83d\\ude00\"] ")"#,
+                "r"
+            )?,
+            VarType::Array(vec![
+                VarType::Null,
+                VarType::Int(-3),
+                VarType::Float(100.0),
+                VarType::String("é😀".to_string())
+            ])
+        );
+        let err = get_var(r#"let r = @parse_json("{\"a\" 1}")"#, "r").unwrap_err();
+        assert!(
+            err.starts_with("parse_json: expected ':' at line 1, column 6"),
+            "{}",
+            err
+        );
+        assert!(get_var(r#"let r = @parse_json("[1,]")"#, "r").is_err());
+        assert!(get_var(r#"let r = @parse_json("1 2")"#, "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_env() -> Result<(), String> {
+        std::env::set_var("TEST_PARSE_ENV_SET", "value");
+        std::env::remove_var("TEST_PARSE_ENV_UNSET");
+        assert_eq!(
+            get_var(r#"let r = @env("TEST_PARSE_ENV_SET")"#, "r")?,
+            VarType::String("value".to_string())
+        );
+        assert_eq!(
+            get_var(r#"let r = @env("TEST_PARSE_ENV_UNSET")"#, "r")?,
+            VarType::Null
+        );
+        assert_eq!(
+            get_var(r#"let r = @env("TEST_PARSE_ENV_UNSET", "fallback")"#, "r")?,
+            VarType::String("fallback".to_string())
+        );
+        assert!(get_var("let r = @env()", "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_now() -> Result<(), String> {
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        let first = i64::try_from(decoder.eval_str("@time_now_millis()")?)?;
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let second = i64::try_from(decoder.eval_str("@time_now_millis()")?)?;
+        assert!(first > 0 && second > first, "{} {}", first, second);
+        let seconds = i64::try_from(decoder.eval_str("@time_now()")?)?;
+        assert!(seconds > 0 && seconds <= second / 1000 + 1);
+        assert!(decoder.eval_str("@time_now(1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_random() -> Result<(), String> {
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            let f = f64::try_from(decoder.eval_str("@random()")?)?;
+            assert!((0.0..1.0).contains(&f), "{}", f);
+            let i = i64::try_from(decoder.eval_str("@random_int(-2, 3)")?)?;
+            assert!((-2..=3).contains(&i), "{}", i);
+            seen[(i + 2) as usize] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+        assert_eq!(decoder.eval_str("@random_int(7, 7)")?, VarType::Int(7));
+        assert!(decoder.eval_str("@random_int(1, 0)").is_err());
+        // 同じ種なら同じ列になる
+        let (mut a, mut b) = (ScopeManager::new(), ScopeManager::new());
+        let mut a = Decoder::new(&mut a).seed(42);
+        let mut b = Decoder::new(&mut b).seed(42);
+        assert_eq!(a.eval_str("@random()")?, b.eval_str("@random()")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rounding() -> Result<(), String> {
+        let call = |src: &str| get_var(&format!("let r = {}", src), "r");
+        assert_eq!(call("@floor(2.7)")?, VarType::Int(2));
+        assert_eq!(call("@ceil(2.1)")?, VarType::Int(3));
+        assert_eq!(call("@round(-2.5)")?, VarType::Int(-3));
+        assert_eq!(call("@trunc(-2.7)")?, VarType::Int(-2));
+        assert_eq!(call("@floor(5)")?, VarType::Int(5));
+        assert_eq!(call("@floor(1e300)")?, VarType::Float(1e300));
+        assert!(call("@ceil(\"1\")").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_assert() -> Result<(), String> {
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        assert_eq!(decoder.eval_str("@assert(1 < 2)")?, VarType::Null);
+        assert_eq!(
+            decoder.eval_str("@assert_eq([1, \"a\"], [1, \"a\"])")?,
+            VarType::Null
+        );
+        let err = decoder.eval_str("let a = 1\n@assert(a > 1)").unwrap_err();
+        assert!(
+            err.starts_with("assertion failed (line 2, column 1)"),
+            "{}",
+            err
+        );
+        let err = decoder.eval_str("@assert_eq(1, \"1\")").unwrap_err();
+        assert!(err.starts_with("assertion failed: 1 != \"1\""), "{}", err);
+        assert!(decoder.eval_str("@assert(1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_contains() -> Result<(), String> {
+        let call = |src: &str| get_var(&format!("let r = {}", src), "r");
+        assert_eq!(call(r#"@contains("hello", "ell")"#)?, VarType::Bool(true));
+        assert_eq!(call(r#"@contains("hello", "xyz")"#)?, VarType::Bool(false));
+        assert_eq!(call(r#"@contains([1, "a"], "a")"#)?, VarType::Bool(true));
+        assert_eq!(call(r#"@contains([1, "a"], 2)"#)?, VarType::Bool(false));
+        assert_eq!(call(r#"@starts_with("hello", "he")"#)?, VarType::Bool(true));
+        assert_eq!(call(r#"@ends_with("hello", "he")"#)?, VarType::Bool(false));
+        assert!(call(r#"@contains("hello", 1)"#).is_err());
+        assert!(call(r#"@ends_with(1, "1")"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_join() -> Result<(), String> {
+        assert_eq!(
+            get_var(r#"let r = @join(["a", "b"], "-") == "a-b""#, "r")?,
+            VarType::Bool(true)
+        );
+        assert_eq!(
+            get_var(r#"let r = @join(@split("x,y,z", ","), ", ")"#, "r")?,
+            VarType::String("x, y, z".to_string())
+        );
+        assert_eq!(
+            get_var(r#"let r = @join([], "-")"#, "r")?,
+            VarType::String(String::new())
+        );
+        let err = get_var(r#"let r = @join(["a", 2], "-")"#, "r").unwrap_err();
+        assert!(
+            err.starts_with("join: element 1 is not a string"),
+            "{}",
+            err
+        );
+        assert!(get_var(r#"let r = @join("ab", "-")"#, "r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_and_reverse() -> Result<(), String> {
+        let call = |src: &str| get_var(&format!("let r = {}", src), "r");
+        assert_eq!(call("@sort([3, 1, 2])")?, VarType::from(vec![1i64, 2, 3]));
+        assert_eq!(
+            call("@sort([3, 1, 2], true)")?,
+            VarType::from(vec![3i64, 2, 1])
+        );
+        assert_eq!(
+            call("@sort([2.5, 1, -0.5])")?,
+            VarType::Array(vec![
+                VarType::Float(-0.5),
+                VarType::Int(1),
+                VarType::Float(2.5)
+            ])
+        );
+        assert_eq!(
+            call(r#"@sort(["b", "a", "c"])"#)?,
+            VarType::from(vec!["a", "b", "c"])
+        );
+        assert_eq!(call(r#"@reverse([1, "a"])"#)?, call(r#"["a", 1]"#)?);
+        assert!(call(r#"@sort([1, "a"])"#).is_err());
+        assert!(call("@sort([1], 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_println() -> Result<(), String> {
+        // printlnとstrは同じ表示形式を使う
+        let src =
+            r#"let v = ["hi", { "k": "v" }, (1, 2.5)] let s = @str(v) let r = @println("hi", v)"#;
+        assert_eq!(get_var(src, "r")?, VarType::Null);
+        assert_eq!(
+            get_var(src, "s")?,
+            VarType::String("[hi, {k: v}, (1, 2.5)]".to_string())
+        );
+        assert_eq!(get_var("let r = @println()", "r")?, VarType::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn test_previous_token() -> Result<(), String> {
+        let tokens = Tokenizer::new().tokenize("let a = 1")?;
+        let mut parser = Parser::new(&tokens);
+        assert_eq!(parser.previous_token(1), None);
+        assert_eq!(parser.previous_token(0), Some(&tokens[0]));
+        parser.next_tokens();
+        parser.next_tokens();
+        assert_eq!(
+            parser.previous_token(1).map(|t| t.value.as_str()),
+            Some("a")
+        );
+        assert_eq!(
+            parser.previous_token(2).map(|t| t.value.as_str()),
+            Some("let")
+        );
+        assert_eq!(parser.previous_token(3), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_comment() -> Result<(), String> {
+        assert_eq!(
+            get_var(
+                "let a = /* 1 */ 2 /* 複数行の\nコメント */ let b = a*/**/3",
+                "b"
+            )?,
+            VarType::Int(6)
+        );
+        let err = Tokenizer::new()
+            .tokenize("let a = 1\n  /* unterminated")
+            .unwrap_err();
+        assert_eq!(err, "ブロックコメントが閉じられていませんよ 2行 3列");
+        assert!(Tokenizer::new().tokenize("/* a *").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_block_comment() -> Result<(), String> {
+        assert_eq!(
+            get_var(
+                "let a = /* outer /* inner /* deepest */ */ still outer */ 1",
+                "a"
+            )?,
+            VarType::Int(1)
+        );
+        let err = Tokenizer::new()
+            .tokenize("/* outer /* inner */ 1")
+            .unwrap_err();
+        assert_eq!(err, "ブロックコメントが閉じられていませんよ 1行 1列");
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_source() -> Result<(), String> {
+        let parse = |src: &str| -> Result<Vec<Box<Node>>, String> {
+            let tokens = Tokenizer::new().tokenize(src)?;
+            program(&mut Parser::new(&tokens))
+        };
+        let src = r#"let a = (1 + 2) * 3 - (4 - 5) let b = (2 ** 3) ** 2 + -(a ** 2) + (-a) ** 2
+            const c = !(a < b) and not false let s = "q\"${a}\n$" + 'x' + '\''
+            let m = { "k": [1, (2,), ()], "t": (a, b) } m["k"] = m["t"].1
+            let r = { let x = a++; x-- ; match x { 1 => "one", _ => { x % 2; } } }"#;
+        let nodes = parse(src)?;
+        let source = program_to_source(&nodes);
+        assert!(
+            source.contains("let a = (1 + 2) * 3 - (4 - 5);\n"),
+            "{}",
+            source
+        );
+        assert!(
+            source.contains("let b = (2 ** 3) ** 2 + -a ** 2 + (-a) ** 2;\n"),
+            "{}",
+            source
+        );
+        assert!(
+            source.contains(r#"let s = "q\"" + "${a}" + "\n\$" + 'x' + '\'';"#),
+            "{}",
+            source
+        );
+        assert_eq!(parse("(-a)[0].1")?[0].to_source(), "(-a)[0].1");
+        // strを隠しても${}の意味は変わらない
+        let shadowed = parse(r#"let str = fn(v) => 0 let s = "v=${"\"}"}""#)?;
+        let formatted = program_to_source(&shadowed);
+        assert!(
+            formatted.contains(r#"let s = "v=" + "${"\"}"}""#),
+            "{}",
+            formatted
+        );
+        let mut scope_manager = ScopeManager::new();
+        Decoder::new(&mut scope_manager).decode(&parse(&formatted)?)?;
+        assert_eq!(
+            scope_manager.get_variable("s"),
+            Some(VarType::String("v=\"}".to_string()))
+        );
+        for (src, expected) in [
+            ("- -a", "-(-a)"),
+            ("-(-1)", "-(-1)"),
+            ("!!a", "!!a"),
+            ("-(1).0", "-(1).0"),
+            ("(2)[0]", "(2)[0]"),
+            ("(\"ab\")[1]", "(\"ab\")[1]"),
+        ] {
+            let formatted = parse(src)?[0].to_source();
+            assert_eq!(formatted, expected);
+            assert_eq!(parse(&formatted)?[0].to_source(), expected);
+        }
+        // もう一度パースして戻しても同じソースになり、実行結果も同じ
+        let reparsed = parse(&source)?;
+        assert_eq!(program_to_source(&reparsed), source);
+        let (mut original, mut formatted) = (ScopeManager::new(), ScopeManager::new());
+        Decoder::new(&mut original).decode(&nodes)?;
+        Decoder::new(&mut formatted).decode(&reparsed)?;
+        assert_eq!(original.scopes, formatted.scopes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_declaration() -> Result<(), String> {
+        let src = "l a, b, c = 1, 2, 3; let d = a + b + c";
+        assert_eq!(get_var(src, "a")?, VarType::Int(1));
+        assert_eq!(get_var(src, "b")?, VarType::Int(2));
+        assert_eq!(get_var(src, "c")?, VarType::Int(3));
+        assert_eq!(get_var(src, "d")?, VarType::Int(6));
+        // 右辺は宣言の前にすべて評価される
+        assert_eq!(get_var("let a = 1 let a, b = 5, a", "b")?, VarType::Int(1));
+        assert!(get_var("const x, y = 1, 2 y = 3", "y").is_err());
+        let err = get_var("let a, b = 1", "a").unwrap_err();
+        assert_eq!(err, "Expected 2 values for a, b but found 1");
+        assert!(get_var("let a, b = 1, 2, 3", "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tuple_assign() -> Result<(), String> {
+        let src = "let a, b = 1, 2 (a, b) = (b, a)";
+        assert_eq!(get_var(src, "a")?, VarType::Int(2));
+        assert_eq!(get_var(src, "b")?, VarType::Int(1));
+        assert_eq!(
+            get_var("let a, b = 0, 0 (a, b) = [3, 4]", "b")?,
+            VarType::Int(4)
+        );
+        let err = get_var("let a, b = 1, 2 (a, b) = (1, 2, 3)", "a").unwrap_err();
+        assert!(
+            err.starts_with("Expected 2 values for (a, b) but found 3"),
+            "{}",
+            err
+        );
+        assert!(get_var("let a = 1 (a, b) = (1, 2)", "a").is_err());
+        assert!(get_var("let a = 1 (a, 1) = (1, 2)", "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lambda() -> Result<(), String> {
+        let src = "let n = 10 let add = fn(a, b) => a + b + n n = 0
+            let r = @add(1, 2) let inc = fn(x) => x + 1 let s = @inc(@add(0, 0))
+            let t = @type_of(inc) + @str(inc)";
+        // nは作られたときの値が使われる
+        assert_eq!(get_var(src, "r")?, VarType::Int(13));
+        assert_eq!(get_var(src, "s")?, VarType::Int(11));
+        assert_eq!(
+            get_var(src, "t")?,
+            VarType::String("function<fn(x)>".to_string())
+        );
+        // 引数は外側の同じ名前を隠し、呼び出した側の変数は見えない
+        assert_eq!(
+            get_var("let x = 1 let f = fn(x) => x * 2 let r = @f(5) + x", "r")?,
+            VarType::Int(11)
+        );
+        assert!(get_var("let f = fn() => y let y = 1 let r = @f()", "r").is_err());
+        assert!(get_var("let f = fn(a) => a let r = @f()", "r").is_err());
+        // 取り込んだ定数は関数の中でも書き換えられない
+        let err =
+            get_var("const k = 1 let f = fn() => { k = 2; k } let r = @f()", "r").unwrap_err();
+        assert!(err.starts_with("cannot assign to constant: k"), "{}", err);
+        assert_eq!(
+            get_var(
+                "const k = 1 let f = fn() => { let k = 2; k } let r = @f()",
+                "r"
+            )?,
+            VarType::Int(2)
+        );
+        assert_eq!(
+            get_var("const k = 1 { let k = 3 let f = fn() => { k = 4; k } let r = @f() global let s = r }", "s")?,
+            VarType::Int(4)
+        );
+        assert_eq!(
+            get_var(
+                "let apply = fn(g, v) => @g(v) let r = @apply(fn(v) => v * 3, 4)",
+                "r"
+            )?,
+            VarType::Int(12)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit() -> Result<(), String> {
+        let tokens = Tokenizer::new().tokenize("let a = 1 @exit(3) let b = 2")?;
+        let nodes = program(&mut Parser::new(&tokens))?;
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        // @exitの後の文は実行されず、終了コードが呼び出し元に返る
         decoder.decode(&nodes)?;
+        assert_eq!(decoder.exit_code(), Some(3));
+        assert_eq!(scope_manager.get_variable("a"), Some(VarType::Int(1)));
+        assert_eq!(scope_manager.get_variable("b"), None);
+        // 関数の中から呼んでも止まる
+        let mut scope_manager = ScopeManager::new();
+        let mut decoder = Decoder::new(&mut scope_manager);
+        decoder.eval_str("let f = fn() => @exit() @f() let c = 1")?;
+        assert_eq!(decoder.exit_code(), Some(0));
+        assert!(get_var("let x = @exit(\"no\")", "x").is_err());
+        // 式の奥から呼ばれても止まる
+        assert_eq!(decoder.eval_str("let s = \"${@f()}\" + 1")?, VarType::Null);
+        assert_eq!(decoder.exit_code(), Some(0));
+        // 一度@exitしても後のエラーは握りつぶされない
+        assert!(decoder.eval_str("undefined_thing + 1").is_err());
+        assert_eq!(decoder.exit_code(), None);
+        assert!(decoder.eval_str("1 / 0").is_err());
+        let err = get_var("@exit(4294967299)", "x").unwrap_err();
+        assert!(
+            err.starts_with("exit: code out of range: 4294967299"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_enum() -> Result<(), String> {
+        let src = "enum Color { Red, Green, Blue, } let c = Color::Green
+            let name = match c { Color::Red => \"red\", Color::Green => \"green\", _ => \"other\" }
+            let same = c == Color::Green let diff = c != Color::Blue
+            let s = @str(c) + \" \" + @type_of(c)";
+        assert_eq!(get_var(src, "name")?, VarType::String("green".to_string()));
+        assert_eq!(get_var(src, "same")?, VarType::Bool(true));
+        assert_eq!(get_var(src, "diff")?, VarType::Bool(true));
+        assert_eq!(
+            get_var(src, "s")?,
+            VarType::String("Color::Green enum".to_string())
+        );
+        assert!(get_var("enum A { X } let a = A::Y", "a").is_err());
+        assert!(get_var("let a = B::X", "a").is_err());
+        assert!(get_var("enum A { X } enum A { Y }", "a").is_err());
+        assert!(get_var("enum A { X, X }", "a").is_err());
+        // 列挙子と他の値は比べられない
+        assert!(get_var("enum A { X } let a = A::X == 1", "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_integer_overflow() -> Result<(), String> {
+        let err = get_var("let a = 3037000500 * 3037000500", "a").unwrap_err();
+        assert!(err.starts_with("Integer overflow: 3037000500 * 3037000500 (line 1,"));
+        assert!(get_var("let a = 9223372036854775807 + 1", "a").is_err());
+        assert!(get_var("let a = 0 - 9223372036854775807 - 2", "a").is_err());
+        let err = get_var("let a = 1 + 99999999999999999999", "a").unwrap_err();
+        assert!(
+            err.starts_with(
+                "Integer literal out of range: 99999999999999999999 (line 1, column 13)"
+            ),
+            "{}",
+            err
+        );
+        assert_eq!(
+            get_var("let a = -9223372036854775808", "a")?,
+            VarType::Int(i64::MIN)
+        );
+        assert!(get_var("let a = 9223372036854775808", "a").is_err());
+        assert_eq!(
+            get_var("let a = 3037000499 * 3037000499", "a")?,
+            VarType::Int(9223372030926249001)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mixed_arithmetic() -> Result<(), String> {
+        assert_eq!(get_var("let a = 2 + 3.0", "a")?, VarType::Float(5.0));
+        assert_eq!(get_var("let a = 2.5 - 1", "a")?, VarType::Float(1.5));
+        assert_eq!(get_var("let a = 2 * 1.5", "a")?, VarType::Float(3.0));
+        assert_eq!(get_var("let a = 7 / 2.0", "a")?, VarType::Float(3.5));
+        assert_eq!(get_var("let a = 7.5 % 2", "a")?, VarType::Float(1.5));
+        assert_eq!(
+            get_var("let a = 2 ** 0.5 * 2 ** 0.5", "a")?,
+            VarType::Float(2.0000000000000004)
+        );
+        assert!(get_var("let a = 1.0 / 0", "a").is_err());
+        // 比較も浮動小数点数にそろえる
+        let src = "let lt = 1 < 2.5 let eq = 1 == 1.0 let ne = 2.5 != 2 let ge = 3.0 >= 3
+            let root = @sqrt(4) == 2";
+        for name in ["lt", "eq", "ne", "ge", "root"] {
+            assert_eq!(get_var(src, name)?, VarType::Bool(true), "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_repeat() -> Result<(), String> {
+        assert_eq!(
+            get_var("let a = \"ab\" * 3", "a")?,
+            VarType::String("ababab".to_string())
+        );
+        assert_eq!(
+            get_var("let a = \"ab\" * 0", "a")?,
+            VarType::String("".to_string())
+        );
+        assert!(get_var("let a = \"ab\" * -1", "a").is_err());
+        assert!(get_var("let a = \"ab\" * 1.5", "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_ordering() -> Result<(), String> {
+        let src = "let lt = \"apple\" < \"banana\" let gt = \"b\" > \"abc\"
+            let le = \"a\" <= \"a\" let ge = \"A\" >= \"a\"";
+        assert_eq!(get_var(src, "lt")?, VarType::Bool(true));
+        assert_eq!(get_var(src, "gt")?, VarType::Bool(true));
+        assert_eq!(get_var(src, "le")?, VarType::Bool(true));
+        assert_eq!(get_var(src, "ge")?, VarType::Bool(false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bool_comparison() -> Result<(), String> {
+        let src =
+            "let done = 1 < 2 let a = done == true let b = true != false let c = false == true";
+        assert_eq!(get_var(src, "a")?, VarType::Bool(true));
+        assert_eq!(get_var(src, "b")?, VarType::Bool(true));
+        assert_eq!(get_var(src, "c")?, VarType::Bool(false));
+        let err = get_var("let a = 1 == \"1\"", "a").unwrap_err();
+        assert!(err.starts_with("Cannot compare i32 with string: Int(1) == String(\"1\")"));
+        let err = get_var("let a = 1.5 < \"2\"", "a").unwrap_err();
+        assert!(err.starts_with("Cannot compare f64 with string"), "{}", err);
+        let err = get_var("let a = true == [true]", "a").unwrap_err();
+        assert!(err.starts_with("Cannot compare bool with array"), "{}", err);
+        // 整数と浮動小数点数は型の違いとして扱わない
+        assert_eq!(get_var("let a = 2 != 2.0", "a")?, VarType::Bool(false));
+        // 同じ型でも大小を比べられないものはそのまま
+        let err = get_var("let a = true < false", "a").unwrap_err();
+        assert!(err.starts_with("Unsupported operand types for <"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unused_variables() -> Result<(), String> {
+        let src = "let used = 1\nlet unused = 2\nlet _ignored = 3\nlet s = \"${used}\"
+            let f = fn(x) => x + 1 @f(1)";
+        let tokens = Tokenizer::new().tokenize(src)?;
+        let nodes = program(&mut Parser::new(&tokens))?;
+        // sも読まれていないので警告され、@f(1)で呼ばれたfは警告されない
+        assert_eq!(
+            unused_variables(&nodes),
+            vec![
+                "unused variable: unused (line 2, column 1)".to_string(),
+                "unused variable: s (line 4, column 1)".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_undeclared_variable() -> Result<(), String> {
+        let err = get_var("let total = 1 let a = totl + 1", "a").unwrap_err();
+        assert!(
+            err.starts_with("Variable not found: totl (line 1,"),
+            "{}",
+            err
+        );
+        let err = get_var("let a = b let b = 1", "a").unwrap_err();
+        assert!(err.starts_with("Variable not found: b"), "{}", err);
+        let err = get_var("count = 1", "count").unwrap_err();
+        assert!(err.starts_with("Variable not found: count"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_declaration() -> Result<(), String> {
+        // 通常の宣言はブロックの中だけで見える
+        assert!(get_var("{ let x = 1 } let y = x", "y").is_err());
+        let src =
+            "let base = 1 { let local = 2 global let x = base + local global const k, m = 3, 4; }
+            let y = x + k + m";
+        assert_eq!(get_var(src, "y")?, VarType::Int(10));
+        assert!(get_var("{ global const k = 1 } k = 2", "k").is_err());
+        assert!(get_var("global x = 1", "x").is_err());
+        // 関数の中から宣言しても一番外側に残る
+        let src = "let f = fn() => { global let x = 1; x + 1 } let r = @f() let y = x";
+        assert_eq!(get_var(src, "r")?, VarType::Int(2));
+        assert_eq!(get_var(src, "y")?, VarType::Int(1));
+        let src = "let g = fn() => { global const z = 5; 0 } let f = fn(h) => @h() @f(g) let y = z";
+        assert_eq!(get_var(src, "y")?, VarType::Int(5));
+        // 途中の関数からも呼び出しが戻った後に見える
+        let src = "let g = fn() => { global let z = 5; 0 }
+            let f = fn(h) => { @h(); z } let r = @f(g)";
+        assert_eq!(get_var(src, "r")?, VarType::Int(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_call_depth() -> Result<(), String> {
+        // 自分自身を呼び続ける関数はスタックあふれではなくエラーになる
+        let err = get_var("let f = fn(g) => @g(g)\nlet r = @f(f)", "r").unwrap_err();
+        assert!(
+            err.starts_with("Maximum call depth exceeded: g (64) (line 1, column 18)"),
+            "{}",
+            err
+        );
+        let src = "let count = fn(g, n) => match n { 0 => 0, _ => @g(g, n - 1) + 1 } let r = @count(count, 5)";
+        let tokens = Tokenizer::new().tokenize(src)?;
+        let nodes = program(&mut Parser::new(&tokens))?;
+        let mut scope_manager = ScopeManager::new();
+        assert!(Decoder::new(&mut scope_manager)
+            .max_call_depth(5)
+            .decode(&nodes)
+            .is_err());
+        let mut scope_manager = ScopeManager::new();
+        Decoder::new(&mut scope_manager)
+            .max_call_depth(6)
+            .decode(&nodes)?;
+        assert_eq!(scope_manager.get_variable("r"), Some(VarType::Int(5)));
         Ok(())
     }
 }
@@ -8,25 +8,29 @@ fn main() -> Result<(), String> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         eprintln!("ファイル名を指定してください");
-        return Err(format!("ファイル名がないお;;"));
+        return Err("ファイル名がないお;;".to_string());
     }
     let file_name = &args[1];
     match read_file(file_name) {
         Ok(contents) => {
             println!("ファイルの内容:\n{}", contents);
-            let temp_src = String::from(contents);
-            let src = temp_src.replace("\r", "");
             // トークナイズ
             let tokenizer = Tokenizer::new();
-            let tokens = tokenizer.tokenize(&src)?;
+            let tokens = tokenizer.tokenize(&contents)?;
             let mut parser = Parser::new(&tokens);
             println!("tokens: {:?}", tokens);
             let mut scope_manager = ScopeManager::new();
             // パース
             let nodes = program(&mut parser)?;
-            let mut decoder = Decoder::new(&parser, &mut scope_manager);
+            for warning in unused_variables(&nodes) {
+                eprintln!("warning: {}", warning);
+            }
+            let mut decoder = Decoder::new(&mut scope_manager).source(&contents);
             // 実行
             decoder.decode(&nodes)?;
+            if let Some(code) = decoder.exit_code() {
+                std::process::exit(code);
+            }
         }
         Err(e) => {
             eprintln!("ファイルを読み込めませんでした: {}", e);
@@ -1,22 +1,253 @@
 use crate::tokenizer::*;
 use crate::types::*;
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::Error;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Node {
     node_type: NodeType,
     value: String,
-    child: Vec<Box<Node>>,
+    child: Vec<Node>,
+    line: usize,   // ソース上の行 (0は不明)
+    column: usize, // ソース上の列
 }
 impl Node {
-    fn new(node_type: NodeType, child: Vec<Box<Node>>, value: String) -> Node {
+    fn new(node_type: NodeType, child: Vec<Node>, value: String) -> Node {
         Node {
             node_type,
             value,
             child,
+            line: 0,
+            column: 0,
+        }
+    }
+    // ASTをソースコードに戻す (優先順位に必要な括弧だけを付ける)
+    pub fn to_source(&self) -> String {
+        let child = |i: usize| self.child[i].to_source();
+        let list = |nodes: &[Node]| {
+            nodes
+                .iter()
+                .map(Node::to_source)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if let Some((op, left_bp, right_bp)) = binding_power(&self.node_type) {
+            // 左辺の演算子は右の結合がこれより強く、右辺の演算子は左の結合がright_bp以上なら括弧がいらない
+            let left = &self.child[0];
+            let left = match binding_power(&left.node_type) {
+                _ if matches!(left.node_type, NodeType::Lambda(_)) => {
+                    format!("({})", left.to_source())
+                }
+                Some((_, _, child_right_bp)) if child_right_bp <= left_bp => {
+                    format!("({})", left.to_source())
+                }
+                _ if matches!(left.node_type, NodeType::Neg | NodeType::Not)
+                    && UNARY_BP <= left_bp =>
+                {
+                    format!("({})", left.to_source())
+                }
+                _ => left.to_source(),
+            };
+            let right = &self.child[1];
+            let right = match binding_power(&right.node_type) {
+                Some((_, child_left_bp, _)) if child_left_bp < right_bp => {
+                    format!("({})", right.to_source())
+                }
+                _ => right.to_source(),
+            };
+            return format!("{} {} {}", left, op, right);
+        }
+        // 後置演算子の対象は括弧で囲んだ式か一つの項でないといけない
+        // (1).0 が 1.0 と読まれないようにリテラルも括弧で囲む
+        let operand = |node: &Node| {
+            if binding_power(&node.node_type).is_some()
+                || matches!(
+                    node.node_type,
+                    NodeType::Neg
+                        | NodeType::Not
+                        | NodeType::Lambda(_)
+                        | NodeType::Num(_)
+                        | NodeType::Str(_)
+                        | NodeType::Bool(_)
+                        | NodeType::Char(_)
+                )
+            {
+                format!("({})", node.to_source())
+            } else {
+                node.to_source()
+            }
+        };
+        match &self.node_type {
+            NodeType::Var(name) => name.clone(),
+            NodeType::VarDecl => format!("let {} = {}", self.value, child(0)),
+            NodeType::ConstDecl => format!("const {} = {}", self.value, child(0)),
+            NodeType::MultiDecl => format!(
+                "{} {} = {}",
+                if self.child[0].node_type == NodeType::ConstDecl {
+                    "const"
+                } else {
+                    "let"
+                },
+                self.value,
+                self.child
+                    .iter()
+                    .map(|decl| decl.child[0].to_source())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            NodeType::Global => format!("global {}", child(0)),
+            NodeType::VarAssign => format!("{} = {}", self.value, child(0)),
+            NodeType::TupleAssign => {
+                let last = self.child.len() - 1;
+                format!("({}) = {}", list(&self.child[..last]), child(last))
+            }
+            NodeType::IndexAssign => format!("{}[{}] = {}", self.value, child(0), child(1)),
+            NodeType::Neg | NodeType::Not => {
+                let op = if self.node_type == NodeType::Neg {
+                    "-"
+                } else {
+                    "!"
+                };
+                match binding_power(&self.child[0].node_type) {
+                    Some((_, left_bp, _)) if left_bp < UNARY_BP => format!("{}({})", op, child(0)),
+                    // --はデクリメントとして読まれるので括弧で分ける
+                    _ if op == "-" && child(0).starts_with('-') => format!("-({})", child(0)),
+                    _ => format!("{}{}", op, child(0)),
+                }
+            }
+            NodeType::PostInc => format!("{}++", child(0)),
+            NodeType::PostDec => format!("{}--", child(0)),
+            NodeType::Index => format!("{}[{}]", operand(&self.child[0]), child(1)),
+            NodeType::TupleField => format!("{}.{}", operand(&self.child[0]), self.value),
+            NodeType::Num(number) => number.clone(),
+            NodeType::Bool(value) => value.to_string(),
+            NodeType::Str(string) => format!("\"{}\"", escape_source(string, '"')),
+            NodeType::Char(ch) => format!("'{}'", escape_source(&ch.to_string(), '\'')),
+            NodeType::Call(func_name) => format!("@{}({})", func_name, list(&self.child)),
+            // @strに戻すと同じ名前の変数に隠されるので文字列の形に戻す
+            NodeType::Interpolation => format!("\"${{{}}}\"", child(0)),
+            NodeType::Array => format!("[{}]", list(&self.child)),
+            NodeType::Tuple if self.child.len() == 1 => format!("({},)", child(0)),
+            NodeType::Tuple => format!("({})", list(&self.child)),
+            NodeType::Map if self.child.is_empty() => "{}".to_string(),
+            NodeType::Map => format!(
+                "{{ {} }}",
+                self.child
+                    .chunks(2)
+                    .map(|entry| format!("{}: {}", entry[0].to_source(), entry[1].to_source()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            NodeType::Block(has_tail) => {
+                if self.child.is_empty() {
+                    return "{}".to_string();
+                }
+                let statements: Vec<String> = self.child.iter().map(Node::to_source).collect();
+                let end = if *has_tail { " " } else { "; " };
+                format!("{{ {}{}}}", statements.join("; "), end)
+            }
+            NodeType::Match => format!(
+                "match {} {{ {} }}",
+                child(0),
+                self.child[1..]
+                    .chunks(2)
+                    .map(|arm| format!("{} => {}", arm[0].to_source(), arm[1].to_source()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            NodeType::Wildcard => "_".to_string(),
+            NodeType::Lambda(params) => format!("fn({}) => {}", params.join(", "), child(0)),
+            NodeType::EnumDecl(variants) => {
+                format!("enum {} {{ {} }}", self.value, variants.join(", "))
+            }
+            NodeType::EnumVariant(enum_name) => format!("{}::{}", enum_name, self.value),
+            _ => self.value.clone(),
+        }
+    }
+    // 値を表示しない文 (宣言や代入) かどうか
+    pub fn is_statement(&self) -> bool {
+        matches!(
+            self.node_type,
+            NodeType::VarDecl
+                | NodeType::ConstDecl
+                | NodeType::MultiDecl
+                | NodeType::Global
+                | NodeType::VarAssign
+                | NodeType::IndexAssign
+                | NodeType::TupleAssign
+                | NodeType::EnumDecl(_)
+        )
+    }
+    // 名前ごとの宣言と書き換えの回数を数える
+    fn count_bindings(&self, declared: &mut HashMap<String, usize>, mutated: &mut HashSet<String>) {
+        match &self.node_type {
+            NodeType::VarDecl | NodeType::ConstDecl => {
+                *declared.entry(self.value.clone()).or_insert(0) += 1;
+            }
+            NodeType::VarAssign | NodeType::IndexAssign => {
+                mutated.insert(self.value.clone());
+            }
+            NodeType::PostInc | NodeType::PostDec => {
+                mutated.insert(self.child[0].value.clone());
+            }
+            NodeType::TupleAssign => {
+                for target in &self.child[..self.child.len() - 1] {
+                    mutated.insert(target.value.clone());
+                }
+            }
+            // 引数も同じ名前の宣言として数える
+            NodeType::Lambda(params) => {
+                for param in params {
+                    *declared.entry(param.clone()).or_insert(0) += 1;
+                }
+            }
+            _ => {}
+        }
+        for child in &self.child {
+            child.count_bindings(declared, mutated);
+        }
+    }
+    // 変数の読み込みを定数のノードに置き換える
+    fn inline_constants(&mut self, constants: &HashMap<String, Node>) {
+        if let NodeType::Var(name) = &self.node_type {
+            if let Some(constant) = constants.get(name) {
+                let (line, column) = (self.line, self.column);
+                *self = constant.clone();
+                (self.line, self.column) = (line, column);
+            }
+            return;
+        }
+        for child in &mut self.child {
+            child.inline_constants(constants);
+        }
+    }
+    // 宣言されたノードと読み込まれた変数名を集める
+    fn collect_variables<'n>(&'n self, declared: &mut Vec<&'n Node>, read: &mut HashSet<String>) {
+        match &self.node_type {
+            NodeType::VarDecl | NodeType::ConstDecl => declared.push(self),
+            NodeType::Var(name) => {
+                read.insert(name.clone());
+            }
+            // @nameは同じ名前の関数を持つ変数を読む
+            NodeType::IndexAssign | NodeType::Call(_) => {
+                read.insert(self.value.clone());
+            }
+            _ => {}
+        }
+        for child in &self.child {
+            child.collect_variables(declared, read);
+        }
+    }
+    // 位置が未設定のノードにトークンの位置を設定する
+    fn locate(&mut self, token: &Token) {
+        if self.line == 0 {
+            self.line = token.line;
+            self.column = token.column;
+        }
+        for child in &mut self.child {
+            child.locate(token);
         }
     }
 }
@@ -26,6 +257,8 @@ impl FromStr for VarType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if let Ok(int_value) = s.parse::<i64>() {
             Ok(VarType::Int(int_value))
+        } else if let Ok(float_value) = s.parse::<f64>() {
+            Ok(VarType::Float(float_value))
         } else {
             Ok(VarType::String(String::from(s)))
         }
@@ -34,18 +267,10 @@ impl FromStr for VarType {
 pub struct Parser<'a> {
     pub tokens: &'a Vec<Token>,
     pub pos: usize,
-    pub variables: HashMap<String, VarType>, // VarType enumを直接使用
 }
 impl<'a> Parser<'a> {
-    fn get_variables(&self) -> HashMap<String, VarType> {
-        self.variables.clone()
-    }
     pub fn new(tokens: &'a Vec<Token>) -> Parser<'a> {
-        Parser {
-            tokens,
-            pos: 0,
-            variables: HashMap::new(),
-        }
+        Parser { tokens, pos: 0 }
     }
     pub fn current_tokens(&self) -> Token {
         self.tokens[self.pos].clone()
@@ -56,49 +281,488 @@ impl<'a> Parser<'a> {
     pub fn peek_next(&self, current_pos: usize) -> Option<&Token> {
         self.tokens.get(current_pos + 1)
     }
-    pub fn expr(&mut self) -> Result<Box<Node>, String> {
-        let mut node = self.term()?;
-        while self.current_tokens().token_type == TokenType::Add
-            || self.current_tokens().token_type == TokenType::Sub
+    // i個前のトークン (先頭より前ならNone)
+    pub fn previous_token(&self, i: usize) -> Option<&Token> {
+        self.pos.checked_sub(i).and_then(|pos| self.tokens.get(pos))
+    }
+    // 文を一つ読む (末尾の;は呼び出し側で読む)
+    pub fn statement(&mut self) -> Result<Box<Node>, String> {
+        let token = self.current_tokens();
+        let mut node = match token.token_type {
+            TokenType::LetDecl | TokenType::ConstDecl => self.var_decl()?,
+            TokenType::Enum => self.enum_decl()?,
+            TokenType::Global => {
+                // global let/const はブロックの中でも一番外側のスコープに宣言する
+                self.next_tokens();
+                if !matches!(
+                    self.current_tokens().token_type,
+                    TokenType::LetDecl | TokenType::ConstDecl
+                ) {
+                    return Err(format!(
+                        "Expected 'let' or 'const' after 'global' but found {:?}",
+                        self.current_tokens()
+                    ));
+                }
+                let decl = self.var_decl()?;
+                Box::new(Node::new(
+                    NodeType::Global,
+                    vec![*decl],
+                    "global".to_string(),
+                ))
+            }
+            TokenType::Ident
+                if self.peek_next(self.pos).map(|t| &t.token_type) == Some(&TokenType::Assign) =>
+            {
+                self.var_assign()?
+            }
+            _ => {
+                let target = self.block_or_expr()?;
+                if self.current_tokens().token_type == TokenType::Assign {
+                    self.index_assign(*target)?
+                } else {
+                    target
+                }
+            }
+        };
+        node.locate(&token);
+        Ok(node)
+    }
+    // enum 名前 { 列挙子, ... }
+    fn enum_decl(&mut self) -> Result<Box<Node>, String> {
+        self.next_tokens(); // enumを読み進める
+        if self.current_tokens().token_type != TokenType::Ident {
+            return Err(format!(
+                "Expected enum name after 'enum' but found {:?}",
+                self.current_tokens()
+            ));
+        }
+        let name = self.current_tokens().value.clone();
+        self.next_tokens();
+        if self.current_tokens().token_type != TokenType::LBlockDelimiter {
+            return Err(format!(
+                "Expected '{{' after enum name but found {:?}",
+                self.current_tokens()
+            ));
+        }
+        self.next_tokens();
+        let mut variants: Vec<String> = Vec::new();
+        // 最後の列挙子の後の,は省略できる
+        while self.current_tokens().token_type == TokenType::Ident {
+            let variant = self.current_tokens().value.clone();
+            if variants.contains(&variant) {
+                return Err(format!("Duplicate variant: {}::{}", name, variant));
+            }
+            variants.push(variant);
+            self.next_tokens();
+            if self.current_tokens().token_type != TokenType::Comma {
+                break;
+            }
+            self.next_tokens();
+        }
+        if self.current_tokens().token_type != TokenType::RBlockDelimiter {
+            return Err(format!(
+                "Expected '}}' after enum variants but found {:?}",
+                self.current_tokens()
+            ));
+        }
+        self.next_tokens();
+        Ok(Box::new(Node::new(
+            NodeType::EnumDecl(variants),
+            vec![],
+            name,
+        )))
+    }
+    fn var_decl(&mut self) -> Result<Box<Node>, String> {
+        let decl = self.current_tokens();
+        self.next_tokens(); // LetDecl/ConstDeclトークンを読み進める
+        if self.current_tokens().token_type != TokenType::Ident {
+            return Err(format!(
+                "Expected identifier after '{}' declaration",
+                decl.value
+            ));
+        }
+        // let a, b = 1, 2 のように,区切りで複数宣言できる
+        let mut var_names = vec![self.current_tokens().value.clone()];
+        self.next_tokens();
+        while self.current_tokens().token_type == TokenType::Comma {
+            self.next_tokens();
+            if self.current_tokens().token_type != TokenType::Ident {
+                return Err(format!(
+                    "Expected identifier after ',' but found {:?}",
+                    self.current_tokens()
+                ));
+            }
+            var_names.push(self.current_tokens().value.clone());
+            self.next_tokens();
+        }
+        if self.current_tokens().token_type != TokenType::Assign {
+            return Err(format!(
+                "Expected '=' after variable name but found {:?}",
+                self.current_tokens()
+            ));
+        }
+        self.next_tokens();
+        let mut expr_nodes = vec![*self.expr()?];
+        while var_names.len() > 1 && self.current_tokens().token_type == TokenType::Comma {
+            self.next_tokens();
+            expr_nodes.push(*self.expr()?);
+        }
+        if expr_nodes.len() != var_names.len() {
+            return Err(format!(
+                "Expected {} values for {} but found {}",
+                var_names.len(),
+                var_names.join(", "),
+                expr_nodes.len()
+            ));
+        }
+        let node_type = if decl.token_type == TokenType::ConstDecl {
+            NodeType::ConstDecl
+        } else {
+            NodeType::VarDecl
+        };
+        let mut decls: Vec<Node> = var_names
+            .iter()
+            .zip(expr_nodes)
+            .map(|(name, expr_node)| Node::new(node_type.clone(), vec![expr_node], name.clone()))
+            .collect();
+        if decls.len() == 1 {
+            return Ok(Box::new(decls.remove(0)));
+        }
+        Ok(Box::new(Node::new(
+            NodeType::MultiDecl,
+            decls,
+            var_names.join(", "),
+        )))
+    }
+    // 宣言済みの変数への代入
+    fn var_assign(&mut self) -> Result<Box<Node>, String> {
+        let var_name = self.current_tokens().value.clone();
+        self.next_tokens(); // 変数名
+        self.next_tokens(); // =
+        let expr_node = self.expr()?;
+        Ok(Box::new(Node::new(
+            NodeType::VarAssign,
+            vec![*expr_node],
+            var_name,
+        )))
+    }
+    // 変数[添字] = 式 または (変数, ...) = 式
+    fn index_assign(&mut self, target: Node) -> Result<Box<Node>, String> {
+        if target.node_type == NodeType::Tuple
+            && !target.child.is_empty()
+            && target
+                .child
+                .iter()
+                .all(|node| matches!(node.node_type, NodeType::Var(_)))
+        {
+            self.next_tokens(); // =
+            let expr_node = self.expr()?;
+            let names: Vec<String> = target.child.iter().map(|node| node.value.clone()).collect();
+            let mut child = target.child;
+            child.push(*expr_node);
+            return Ok(Box::new(Node::new(
+                NodeType::TupleAssign,
+                child,
+                names.join(", "),
+            )));
+        }
+        if target.node_type != NodeType::Index
+            || !matches!(target.child[0].node_type, NodeType::Var(_))
+        {
+            return Err("Invalid assignment target".to_string());
+        }
+        self.next_tokens(); // =
+        let expr_node = self.expr()?;
+        let mut child = target.child;
+        let var_name = child.remove(0).value;
+        child.push(*expr_node);
+        Ok(Box::new(Node::new(NodeType::IndexAssign, child, var_name)))
+    }
+    // { "キー": 式, ... }
+    fn map_literal(&mut self) -> Result<Box<Node>, String> {
+        self.next_tokens(); // {を読み進める
+        let mut entries = Vec::new();
+        while self.current_tokens().token_type != TokenType::RBlockDelimiter {
+            let key = self.current_tokens();
+            if key.token_type != TokenType::String {
+                return Err(format!("Expected a string key but found {:?}", key));
+            }
+            self.next_tokens();
+            if self.current_tokens().token_type != TokenType::Colon {
+                return Err(format!(
+                    "Expected ':' after map key but found {:?}",
+                    self.current_tokens()
+                ));
+            }
+            self.next_tokens();
+            let mut key_node =
+                Node::new(NodeType::Str(key.value.clone()), vec![], key.value.clone());
+            key_node.locate(&key);
+            entries.push(key_node);
+            entries.push(*self.expr()?);
+            match self.current_tokens().token_type {
+                TokenType::Comma => self.next_tokens(),
+                TokenType::RBlockDelimiter => {}
+                _ => {
+                    return Err(format!(
+                        "Expected ',' or '}}' in map literal but found {:?}",
+                        self.current_tokens()
+                    ))
+                }
+            }
+        }
+        self.next_tokens(); // }を読み進める
+        Ok(Box::new(Node::new(
+            NodeType::Map,
+            entries,
+            "{}".to_string(),
+        )))
+    }
+    // 文や関数の本体、matchの腕では{}を空のマップではなく空のブロックとして読む
+    fn block_or_expr(&mut self) -> Result<Box<Node>, String> {
+        if self.current_tokens().token_type == TokenType::LBlockDelimiter
+            && self.peek_next(self.pos).map(|t| &t.token_type) == Some(&TokenType::RBlockDelimiter)
         {
-            let current_token = self.current_tokens().clone();
+            return self.block();
+        }
+        self.expr()
+    }
+    fn block(&mut self) -> Result<Box<Node>, String> {
+        self.next_tokens(); // {を読み進める
+        let mut statements = Vec::new();
+        let mut has_tail = false;
+        while self.current_tokens().token_type != TokenType::RBlockDelimiter {
+            if self.current_tokens().token_type == TokenType::Eof {
+                return Err("Expected closing brace '}' but found end of input".to_string());
+            }
+            statements.push(*self.statement()?);
+            // 最後の文に;がなければその値をブロックの値とする
+            has_tail = true;
+            if self.current_tokens().token_type == TokenType::Semi {
+                self.next_tokens();
+                has_tail = false;
+            }
+        }
+        self.next_tokens();
+        Ok(Box::new(Node::new(
+            NodeType::Block(has_tail),
+            statements,
+            "{}".to_string(),
+        )))
+    }
+    // match 式 { パターン => 式, ..., _ => 式 }
+    fn match_expr(&mut self) -> Result<Box<Node>, String> {
+        self.next_tokens(); // matchを読み進める
+        let mut child = vec![*self.expr()?];
+        if self.current_tokens().token_type != TokenType::LBlockDelimiter {
+            return Err(format!(
+                "Expected '{{' after match value but found {:?}",
+                self.current_tokens()
+            ));
+        }
+        self.next_tokens();
+        while self.current_tokens().token_type != TokenType::RBlockDelimiter {
+            let token = self.current_tokens();
+            let pattern = if token.token_type == TokenType::Ident && token.value == "_" {
+                self.next_tokens();
+                Node::new(NodeType::Wildcard, vec![], token.value)
+            } else {
+                *self.expr()?
+            };
+            if self.current_tokens().token_type != TokenType::FatArrow {
+                return Err(format!(
+                    "Expected '=>' after match pattern but found {:?}",
+                    self.current_tokens()
+                ));
+            }
+            self.next_tokens();
+            child.push(pattern);
+            child.push(*self.block_or_expr()?);
+            match self.current_tokens().token_type {
+                TokenType::Comma => self.next_tokens(),
+                TokenType::RBlockDelimiter => (),
+                _ => {
+                    return Err(format!(
+                        "Expected ',' or '}}' after match arm but found {:?}",
+                        self.current_tokens()
+                    ))
+                }
+            }
+        }
+        self.next_tokens();
+        Ok(Box::new(Node::new(
+            NodeType::Match,
+            child,
+            "match".to_string(),
+        )))
+    }
+    // fn(引数, ...) => 式
+    fn lambda(&mut self) -> Result<Box<Node>, String> {
+        self.next_tokens(); // fnを読み進める
+        if self.current_tokens().token_type != TokenType::LParen {
+            return Err(format!(
+                "Expected '(' after fn but found {:?}",
+                self.current_tokens()
+            ));
+        }
+        self.next_tokens();
+        let mut params = Vec::new();
+        while self.current_tokens().token_type == TokenType::Ident {
+            params.push(self.current_tokens().value.clone());
+            self.next_tokens();
+            if self.current_tokens().token_type != TokenType::Comma {
+                break;
+            }
+            self.next_tokens();
+        }
+        if self.current_tokens().token_type != TokenType::RParen {
+            return Err(format!(
+                "Expected ')' after parameters but found {:?}",
+                self.current_tokens()
+            ));
+        }
+        self.next_tokens();
+        if self.current_tokens().token_type != TokenType::FatArrow {
+            return Err(format!(
+                "Expected '=>' after parameters but found {:?}",
+                self.current_tokens()
+            ));
+        }
+        self.next_tokens();
+        let body = self.block_or_expr()?;
+        Ok(Box::new(Node::new(
+            NodeType::Lambda(params),
+            vec![*body],
+            "fn".to_string(),
+        )))
+    }
+    pub fn expr(&mut self) -> Result<Box<Node>, String> {
+        self.expr_bp(0)
+    }
+    // 優先順位法で二項演算子を読む (min_bpより弱い演算子で止まる)
+    fn expr_bp(&mut self, min_bp: u8) -> Result<Box<Node>, String> {
+        let mut node = self.unary()?;
+        loop {
+            let current_token = self.current_tokens();
+            let (node_type, left_bp, right_bp) = match binary_operator(&current_token.token_type) {
+                Some(operator) => operator,
+                None => break,
+            };
+            if left_bp < min_bp {
+                break;
+            }
             self.next_tokens();
+            let rhs = self.expr_bp(right_bp)?;
             node = Box::new(Node::new(
-                match current_token.token_type {
-                    TokenType::Add => NodeType::Add,
-                    TokenType::Sub => NodeType::Sub,
-                    _ => unreachable!(),
-                },
-                vec![node, self.term()?],
-                current_token.value,
+                node_type,
+                vec![*node, *rhs],
+                current_token.value.clone(),
             ));
+            node.locate(&current_token);
         }
         Ok(node)
     }
-    fn term(&mut self) -> Result<Box<Node>, String> {
+    // 前置の単項演算子
+    fn unary(&mut self) -> Result<Box<Node>, String> {
+        let current_token = self.current_tokens();
+        let node_type = match current_token.token_type {
+            TokenType::Sub => NodeType::Neg,
+            TokenType::Not => NodeType::Not,
+            _ => return self.postfix(),
+        };
+        self.next_tokens();
+        let operand = self.expr_bp(UNARY_BP)?;
+        let mut node = Box::new(Node::new(
+            node_type,
+            vec![*operand],
+            current_token.value.clone(),
+        ));
+        node.locate(&current_token);
+        Ok(node)
+    }
+    // 変数への後置の ++ / --
+    fn postfix(&mut self) -> Result<Box<Node>, String> {
         let mut node = self.factor()?;
-        while self.current_tokens().token_type == TokenType::Mul
-            || self.current_tokens().token_type == TokenType::Div
-        {
-            let current_token = self.current_tokens().clone();
+        loop {
+            let current_token = self.current_tokens();
+            let node_type = match current_token.token_type {
+                TokenType::LBracket => {
+                    self.next_tokens();
+                    let index = self.expr()?;
+                    if self.current_tokens().token_type != TokenType::RBracket {
+                        return Err(format!(
+                            "Expected ']' but found {:?}",
+                            self.current_tokens()
+                        ));
+                    }
+                    self.next_tokens();
+                    node = Box::new(Node::new(
+                        NodeType::Index,
+                        vec![*node, *index],
+                        current_token.value.clone(),
+                    ));
+                    node.locate(&current_token);
+                    continue;
+                }
+                TokenType::Dot => {
+                    self.next_tokens();
+                    let field = self.current_tokens();
+                    // t.0.1 は 0.1 が浮動小数点数として読まれるので二つに分ける
+                    let indices: Vec<&str> = match field.token_type {
+                        TokenType::Int => vec![&field.value],
+                        TokenType::Float if !field.value.contains(['e', 'E']) => {
+                            field.value.split('.').collect()
+                        }
+                        _ => {
+                            return Err(format!(
+                                "Expected a tuple index after '.' but found {:?}",
+                                field
+                            ))
+                        }
+                    };
+                    self.next_tokens();
+                    for index in indices {
+                        node = Box::new(Node::new(
+                            NodeType::TupleField,
+                            vec![*node],
+                            index.to_string(),
+                        ));
+                        node.locate(&field);
+                    }
+                    continue;
+                }
+                TokenType::Increment => NodeType::PostInc,
+                TokenType::Decrement => NodeType::PostDec,
+                _ => break,
+            };
+            if !matches!(node.node_type, NodeType::Var(_)) {
+                return Err(format!(
+                    "Operator {} can only be applied to a variable",
+                    current_token.value
+                ));
+            }
             self.next_tokens();
             node = Box::new(Node::new(
-                match current_token.token_type {
-                    TokenType::Mul => NodeType::Mul,
-                    TokenType::Div => NodeType::Div,
-                    _ => unreachable!(),
-                },
-                vec![node, self.factor()?],
-                current_token.value,
+                node_type,
+                vec![*node],
+                current_token.value.clone(),
             ));
+            node.locate(&current_token);
         }
         Ok(node)
     }
 
     fn factor(&mut self) -> Result<Box<Node>, String> {
+        let token = self.current_tokens();
+        let mut node = self.primary()?;
+        node.locate(&token);
+        Ok(node)
+    }
+
+    fn primary(&mut self) -> Result<Box<Node>, String> {
         let current_token = self.current_tokens().clone();
         match current_token.token_type {
-            TokenType::Int => {
+            TokenType::Int | TokenType::Float => {
                 self.next_tokens();
                 Ok(Box::new(Node::new(
                     NodeType::Num(current_token.value.clone()),
@@ -106,18 +770,140 @@ impl<'a> Parser<'a> {
                     current_token.value.clone(),
                 )))
             }
+            TokenType::String => {
+                // 隣り合う文字列リテラルは一つに連結し、${式}は文字列に変換して+でつなぐ
+                let mut parts = Vec::new();
+                let mut string = String::new();
+                loop {
+                    let token = self.current_tokens();
+                    match token.token_type {
+                        TokenType::String => string.push_str(&token.value),
+                        TokenType::Interpolation => {
+                            if !string.is_empty() {
+                                let literal = std::mem::take(&mut string);
+                                parts.push(Node::new(
+                                    NodeType::Str(literal.clone()),
+                                    vec![],
+                                    literal,
+                                ));
+                            }
+                            let expr_node = parse_interpolation(&token)?;
+                            parts.push(Node::new(
+                                NodeType::Interpolation,
+                                vec![expr_node],
+                                "str".to_string(),
+                            ));
+                        }
+                        _ => break,
+                    }
+                    self.next_tokens();
+                }
+                if !string.is_empty() || parts.is_empty() {
+                    parts.push(Node::new(NodeType::Str(string.clone()), vec![], string));
+                }
+                let mut parts = parts.into_iter();
+                let mut node = parts.next().expect("string literal has at least one part");
+                for part in parts {
+                    node = Node::new(NodeType::Add, vec![node, part], "+".to_string());
+                }
+                Ok(Box::new(node))
+            }
+            TokenType::Bool => {
+                self.next_tokens();
+                Ok(Box::new(Node::new(
+                    NodeType::Bool(current_token.value == "true"),
+                    vec![],
+                    current_token.value.clone(),
+                )))
+            }
+            TokenType::Char => {
+                self.next_tokens();
+                let ch = current_token.value.chars().next().unwrap_or_default();
+                Ok(Box::new(Node::new(
+                    NodeType::Char(ch),
+                    vec![],
+                    current_token.value.clone(),
+                )))
+            }
             TokenType::Ident => {
                 self.next_tokens();
                 let ident = current_token.value.clone();
+                if self.current_tokens().token_type == TokenType::PathSep {
+                    // 列挙型::列挙子
+                    self.next_tokens();
+                    if self.current_tokens().token_type != TokenType::Ident {
+                        return Err(format!(
+                            "Expected variant name after '{}::' but found {:?}",
+                            ident,
+                            self.current_tokens()
+                        ));
+                    }
+                    let variant = self.current_tokens().value.clone();
+                    self.next_tokens();
+                    return Ok(Box::new(Node::new(
+                        NodeType::EnumVariant(ident),
+                        vec![],
+                        variant,
+                    )));
+                }
                 Ok(Box::new(Node::new(
                     NodeType::Var(ident.clone()),
                     vec![],
                     ident,
                 )))
             }
+            TokenType::At => {
+                self.next_tokens(); // @を読み進める
+                if self.current_tokens().token_type != TokenType::Ident {
+                    return Err(format!(
+                        "Expected function name after '@' but found {:?}",
+                        self.current_tokens()
+                    ));
+                }
+                let func_name = self.current_tokens().value.clone();
+                self.next_tokens();
+                if self.current_tokens().token_type != TokenType::LParen {
+                    return Err(format!(
+                        "Expected '(' after function name but found {:?}",
+                        self.current_tokens()
+                    ));
+                }
+                self.next_tokens();
+                // 引数を','区切りで読む
+                let mut args = Vec::new();
+                while self.current_tokens().token_type != TokenType::RParen {
+                    args.push(*self.expr()?);
+                    match self.current_tokens().token_type {
+                        TokenType::Comma => self.next_tokens(),
+                        TokenType::RParen => (),
+                        _ => {
+                            return Err(format!(
+                                "Expected ',' or ')' in argument list but found {:?}",
+                                self.current_tokens()
+                            ))
+                        }
+                    }
+                }
+                self.next_tokens();
+                Ok(Box::new(Node::new(
+                    NodeType::Call(func_name.clone()),
+                    args,
+                    func_name,
+                )))
+            }
             TokenType::LParen => {
                 self.next_tokens();
-                let node = self.expr()?;
+                // ,を含むか()ならタプル
+                let mut elements = Vec::new();
+                let mut is_tuple = self.current_tokens().token_type == TokenType::RParen;
+                while self.current_tokens().token_type != TokenType::RParen {
+                    elements.push(*self.expr()?);
+                    if self.current_tokens().token_type != TokenType::Comma {
+                        break;
+                    }
+                    self.next_tokens();
+                    is_tuple = true;
+                }
                 if self.current_tokens().token_type != TokenType::RParen {
                     return Err(format!(
                         "Expected closing parenthesis ')' but found {:?}",
@@ -125,32 +911,57 @@ impl<'a> Parser<'a> {
                     ));
                 }
                 self.next_tokens();
-                Ok(node)
-            }
-
-            TokenType::LetDecl => {
-                self.next_tokens(); // LetDeclトークンを読み進める
-                if let TokenType::Ident = self.current_tokens().token_type {
-                    let var_name = self.current_tokens().value.clone();
-                    // 式になるまでトークンを進める
-                    self.next_tokens();
-                    self.next_tokens();
-
-                    // 式を評価して結果を取得
-                    let expr_node = self.expr()?; // 式を評価
-
-                    // 式の結果を取得して変数にセット
-                    let result = self.eval(&expr_node)?;
-                    self.variables.insert(var_name.clone(), result);
+                if is_tuple {
                     Ok(Box::new(Node::new(
-                        NodeType::Var(var_name.clone()),
-                        vec![],
-                        var_name,
+                        NodeType::Tuple,
+                        elements,
+                        "()".to_string(),
                     )))
                 } else {
-                    Err("Expected identifier after 'int' declaration".to_string())
+                    Ok(Box::new(elements.remove(0)))
+                }
+            }
+
+            // [式, ...]
+            TokenType::LBracket => {
+                self.next_tokens();
+                let mut elements = Vec::new();
+                while self.current_tokens().token_type != TokenType::RBracket {
+                    elements.push(*self.expr()?);
+                    if self.current_tokens().token_type != TokenType::Comma {
+                        break;
+                    }
+                    self.next_tokens();
                 }
+                if self.current_tokens().token_type != TokenType::RBracket {
+                    return Err(format!(
+                        "Expected ']' but found {:?}",
+                        self.current_tokens()
+                    ));
+                }
+                self.next_tokens();
+                Ok(Box::new(Node::new(
+                    NodeType::Array,
+                    elements,
+                    "[]".to_string(),
+                )))
+            }
+            // { "キー": ... } と {} ならマップ、それ以外はブロック
+            TokenType::LBlockDelimiter
+                if matches!(
+                    (
+                        self.peek_next(self.pos).map(|t| &t.token_type),
+                        self.peek_next(self.pos + 1).map(|t| &t.token_type)
+                    ),
+                    (Some(TokenType::RBlockDelimiter), _)
+                        | (Some(TokenType::String), Some(TokenType::Colon))
+                ) =>
+            {
+                self.map_literal()
             }
+            TokenType::LBlockDelimiter => self.block(),
+            TokenType::Match => self.match_expr(),
+            TokenType::Fn => self.lambda(),
             TokenType::Eof => Ok(Box::new(Node::new(
                 NodeType::Error,
                 vec![],
@@ -159,114 +970,753 @@ impl<'a> Parser<'a> {
             _ => Err(format!("Unexpected token: {:?}", current_token)),
         }
     }
+}
 
-    pub fn print_var(&self, node: &Node, index: usize) -> Result<(), String> {
-        let var_name = match self.variables.iter().nth(index) {
-            Some((name, _value)) => name.clone(),
-            None => return Err("Variable index out of bounds".to_string()),
-        };
+// 単項演算子の結合の強さ (**だけがこれより強い)
+const UNARY_BP: u8 = 13;
 
-        match self.variables.get(&var_name) {
-            Some(value) => {
-                println!("var name: {:?}  value: {:?}", var_name, value);
-                Ok(())
-            }
-            None => Err("Variable not found".to_string()),
-        }
+// 二項演算子のノードの種類と左右の結合の強さ
+fn binary_operator(token_type: &TokenType) -> Option<(NodeType, u8, u8)> {
+    match token_type {
+        TokenType::Or => Some((NodeType::Or, 1, 2)),
+        TokenType::And => Some((NodeType::And, 3, 4)),
+        TokenType::Eq => Some((NodeType::Eq, 5, 6)),
+        TokenType::Ne => Some((NodeType::Ne, 5, 6)),
+        TokenType::Lt => Some((NodeType::Lt, 7, 8)),
+        TokenType::Gt => Some((NodeType::Gt, 7, 8)),
+        TokenType::Le => Some((NodeType::Le, 7, 8)),
+        TokenType::Ge => Some((NodeType::Ge, 7, 8)),
+        TokenType::Add => Some((NodeType::Add, 9, 10)),
+        TokenType::Sub => Some((NodeType::Sub, 9, 10)),
+        TokenType::Mul => Some((NodeType::Mul, 11, 12)),
+        TokenType::Div => Some((NodeType::Div, 11, 12)),
+        TokenType::Mod => Some((NodeType::Mod, 11, 12)),
+        // **は右結合
+        TokenType::Pow => Some((NodeType::Pow, 15, 14)),
+        _ => None,
     }
-    // Parser内のeval関数を以下のように更新します
-    pub fn eval(&self, node: &Node) -> Result<VarType, String> {
-        match &node.node_type {
-            NodeType::Add => {
-                let temp_left = VarType::from(self.eval(&node.child[0])?);
-                let temp_right = VarType::from(self.eval(&node.child[1])?);
-                let left = match temp_left {
-                    VarType::Int(int) => int,
-                    _ => -1,
-                };
-                let right = match temp_right {
-                    VarType::Int(int) => int,
-                    _ => -1,
-                };
-                Ok(VarType::Int(left + right))
-            }
-            NodeType::Sub => {
-                let temp_left = VarType::from(self.eval(&node.child[0])?);
-                let temp_right = VarType::from(self.eval(&node.child[1])?);
-                let left = match temp_left {
-                    VarType::Int(int) => int,
-                    _ => -1,
-                };
-                let right = match temp_right {
-                    VarType::Int(int) => int,
-                    _ => -1,
-                };
-                Ok(VarType::Int(left - right))
-            }
-            NodeType::Mul => {
-                let temp_left = VarType::from(self.eval(&node.child[0])?);
-                let temp_right = VarType::from(self.eval(&node.child[1])?);
-                let left = match temp_left {
-                    VarType::Int(int) => int,
-                    _ => -1,
-                };
-                let right = match temp_right {
-                    VarType::Int(int) => int,
-                    _ => -1,
-                };
-                Ok(VarType::Int(left * right))
-            }
-            NodeType::Div => {
-                let temp_left = VarType::from(self.eval(&node.child[0])?);
-                let temp_right = VarType::from(self.eval(&node.child[1])?);
-                let left = match temp_left {
-                    VarType::Int(int) => int,
-                    _ => -1,
-                };
-                let right = match temp_right {
-                    VarType::Int(int) => int,
-                    _ => -1,
-                };
-                Ok(VarType::Int(left / right))
-            }
+}
 
-            // 他のパターンもVarTypeに応じて返り値の型を変更します
-            NodeType::Var(variable_name) => {
-                if let Some(value) = self.variables.get(variable_name) {
-                    Ok(value.clone()) // 変数名に対応する値を返す
-                } else {
-                    Err("Variable not found".to_string())
-                }
+// 二項演算子のノードの記号と左右の結合の強さ
+fn binding_power(node_type: &NodeType) -> Option<(&'static str, u8, u8)> {
+    let (op, token_type) = match node_type {
+        NodeType::Or => ("||", TokenType::Or),
+        NodeType::And => ("&&", TokenType::And),
+        NodeType::Eq => ("==", TokenType::Eq),
+        NodeType::Ne => ("!=", TokenType::Ne),
+        NodeType::Lt => ("<", TokenType::Lt),
+        NodeType::Gt => (">", TokenType::Gt),
+        NodeType::Le => ("<=", TokenType::Le),
+        NodeType::Ge => (">=", TokenType::Ge),
+        NodeType::Add => ("+", TokenType::Add),
+        NodeType::Sub => ("-", TokenType::Sub),
+        NodeType::Mul => ("*", TokenType::Mul),
+        NodeType::Div => ("/", TokenType::Div),
+        NodeType::Mod => ("%", TokenType::Mod),
+        NodeType::Pow => ("**", TokenType::Pow),
+        _ => return None,
+    };
+    let (_, left_bp, right_bp) = binary_operator(&token_type)?;
+    Some((op, left_bp, right_bp))
+}
+
+// 文字列や文字リテラルの中身をソース用にエスケープする
+fn escape_source(string: &str, quote: char) -> String {
+    let mut escaped = String::new();
+    for c in string.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            '\\' => escaped.push_str("\\\\"),
+            '$' => escaped.push_str("\\$"),
+            c if c == quote => {
+                escaped.push('\\');
+                escaped.push(c);
             }
-            NodeType::Num(expression) => expression
-                .parse::<VarType>()
-                .map_err(|_| "Invalid expression".to_string()),
-            _ => Err("Invalid operation".to_string()),
+            c => escaped.push(c),
         }
     }
+    escaped
 }
 
-pub struct ScopeManager {
-    pub scopes: Vec<HashMap<String, VarType>>, // スコープ毎の変数名と値の関連付けを管理するVec
+// プログラム全体をソースコードに戻す
+pub fn program_to_source(program: &[Box<Node>]) -> String {
+    program
+        .iter()
+        .map(|node| format!("{};\n", node.to_source()))
+        .collect()
 }
 
-impl ScopeManager {
-    pub fn new() -> ScopeManager {
-        ScopeManager {
-            scopes: vec![HashMap::new()],
-        } // 初期スコープを作成
+// ${}の中身を式としてパースする
+fn parse_interpolation(token: &Token) -> Result<Node, String> {
+    let mut tokens = Tokenizer::new().tokenize(&token.value)?;
+    // 中の式の位置は文字列リテラルの位置とする
+    for inner in &mut tokens {
+        inner.line = token.line;
+        inner.column = token.column;
     }
-
-    fn create_scope(&mut self) {
-        self.scopes.push(HashMap::new()); // 新しいスコープを作成して追加
+    let mut parser = Parser::new(&tokens);
+    let node = parser.expr()?;
+    if parser.current_tokens().token_type != TokenType::Eof {
+        return Err(format!(
+            "Unexpected token in interpolation: {:?}",
+            parser.current_tokens()
+        ));
     }
+    Ok(*node)
+}
 
-    fn destroy_scope(&mut self) {
+// 二項演算子を値に適用する
+// int_divisionがfalseなら整数同士の/は浮動小数点数で計算する
+fn eval_binary_op(
+    op: &str,
+    left: &VarType,
+    right: &VarType,
+    int_division: bool,
+) -> Result<VarType, String> {
+    let result = match (left, right) {
+        (VarType::Int(_), VarType::Int(0)) if op == "/" || op == "%" => {
+            Err(format!("Division by zero: {:?} {} 0", left, op))
+        }
+        (VarType::Float(_), VarType::Float(r)) if (op == "/" || op == "%") && *r == 0.0 => {
+            Err(format!("Division by zero: {:?} {} 0.0", left, op))
+        }
+        (VarType::Int(l), VarType::Int(r)) if op == "/" && !int_division => {
+            Ok(VarType::Float(*l as f64 / *r as f64))
+        }
+        // 整数のあふれは浮動小数点数にせずエラーにする
+        (VarType::Int(l), VarType::Int(r)) => match op {
+            "+" => l
+                .checked_add(*r)
+                .map(VarType::Int)
+                .ok_or_else(|| format!("Integer overflow: {} + {}", l, r)),
+            "-" => l
+                .checked_sub(*r)
+                .map(VarType::Int)
+                .ok_or_else(|| format!("Integer overflow: {} - {}", l, r)),
+            "*" => l
+                .checked_mul(*r)
+                .map(VarType::Int)
+                .ok_or_else(|| format!("Integer overflow: {} * {}", l, r)),
+            "/" => l
+                .checked_div(*r)
+                .map(VarType::Int)
+                .ok_or_else(|| format!("Integer overflow: {} / {}", l, r)),
+            "%" => l
+                .checked_rem(*r)
+                .map(VarType::Int)
+                .ok_or_else(|| format!("Integer overflow: {} % {}", l, r)),
+            // 負の指数は浮動小数点数で計算する
+            "**" if *r < 0 => Ok(VarType::Float((*l as f64).powf(*r as f64))),
+            "**" => u32::try_from(*r)
+                .ok()
+                .and_then(|r| l.checked_pow(r))
+                .map(VarType::Int)
+                .ok_or_else(|| format!("Integer overflow: {} ** {}", l, r)),
+            _ => Err(format!("Unknown operator: {}", op)),
+        },
+        // 整数と浮動小数点数の組は浮動小数点数にそろえて計算する
+        (VarType::Int(l), VarType::Float(_)) => {
+            eval_binary_op(op, &VarType::Float(*l as f64), right, int_division)
+        }
+        (VarType::Float(_), VarType::Int(r)) => {
+            eval_binary_op(op, left, &VarType::Float(*r as f64), int_division)
+        }
+        (VarType::String(l), VarType::String(r)) if op == "+" => {
+            Ok(VarType::String(format!("{}{}", l, r)))
+        }
+        // "ab" * 3 で文字列を繰り返す
+        (VarType::String(l), VarType::Int(r)) if op == "*" => match usize::try_from(*r) {
+            Ok(count) => Ok(VarType::String(l.repeat(count))),
+            Err(_) => Err(format!("Invalid repeat count: {}", r)),
+        },
+        (VarType::String(_), VarType::Float(r)) if op == "*" => {
+            Err(format!("Repeat count must be an integer: {:?}", r))
+        }
+        (VarType::Float(l), VarType::Float(r)) => match op {
+            "+" => Ok(VarType::Float(l + r)),
+            "-" => Ok(VarType::Float(l - r)),
+            "*" => Ok(VarType::Float(l * r)),
+            "/" => Ok(VarType::Float(l / r)),
+            "%" => Ok(VarType::Float(l % r)),
+            "**" => Ok(VarType::Float(l.powf(*r))),
+            _ => Err(format!("Unknown operator: {}", op)),
+        },
+        _ => Err(format!(
+            "Unsupported operand types for {}: {:?} and {:?}",
+            op, left, right
+        )),
+    }?;
+    // NaNや無限大はエラーにする
+    match result {
+        VarType::Float(v) if !v.is_finite() => Err(format!(
+            "Result of {:?} {} {:?} is not a finite number: {}",
+            left, op, right, v
+        )),
+        _ => Ok(result),
+    }
+}
+
+// 比較演算子を値に適用する
+fn eval_binary_condition(op: &str, left: &VarType, right: &VarType) -> Result<VarType, String> {
+    let ordering = match (left, right) {
+        (VarType::Int(l), VarType::Int(r)) => l.partial_cmp(r),
+        (VarType::Float(l), VarType::Float(r)) => l.partial_cmp(r),
+        // 四則演算と同じく整数は浮動小数点数にそろえて比べる
+        (VarType::Int(l), VarType::Float(r)) => (*l as f64).partial_cmp(r),
+        (VarType::Float(l), VarType::Int(r)) => l.partial_cmp(&(*r as f64)),
+        // 文字列は辞書順で比べる
+        (VarType::String(l), VarType::String(r)) => l.partial_cmp(r),
+        (VarType::Enum(l, lv), VarType::Enum(r, rv)) if op == "==" || op == "!=" => {
+            (l, lv).partial_cmp(&(r, rv))
+        }
+        (VarType::Bool(l), VarType::Bool(r)) if op == "==" || op == "!=" => l.partial_cmp(r),
+        // 型が違う値同士は比べられない (整数と浮動小数点数は上でそろえてある)
+        _ if infer_type(left) != infer_type(right) => {
+            return Err(format!(
+                "Cannot compare {} with {}: {:?} {} {:?}",
+                infer_type(left),
+                infer_type(right),
+                left,
+                op,
+                right
+            ))
+        }
+        _ => {
+            return Err(format!(
+                "Unsupported operand types for {}: {:?} and {:?}",
+                op, left, right
+            ))
+        }
+    };
+    let result = match op {
+        "==" => ordering == Some(std::cmp::Ordering::Equal),
+        "!=" => ordering != Some(std::cmp::Ordering::Equal),
+        "<" => ordering == Some(std::cmp::Ordering::Less),
+        ">" => ordering == Some(std::cmp::Ordering::Greater),
+        "<=" => matches!(
+            ordering,
+            Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)
+        ),
+        ">=" => matches!(
+            ordering,
+            Some(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal)
+        ),
+        _ => return Err(format!("Unknown operator: {}", op)),
+    };
+    Ok(VarType::Bool(result))
+}
+
+// 真偽値を取り出す
+fn to_bool(op: &str, value: &VarType) -> Result<bool, String> {
+    match value {
+        VarType::Bool(v) => Ok(*v),
+        other => Err(format!("{}: expected a bool but found {:?}", op, other)),
+    }
+}
+
+// システム関数の引数の数を確認
+fn check_arg_count(func_name: &str, args: &[VarType], expected: usize) -> Result<(), String> {
+    if args.len() != expected {
+        return Err(format!(
+            "{}: expected {} argument(s) but got {}",
+            func_name,
+            expected,
+            args.len()
+        ));
+    }
+    Ok(())
+}
+
+// 文字列の引数を取り出す
+fn to_str<'v>(func_name: &str, value: &'v VarType) -> Result<&'v str, String> {
+    match value {
+        VarType::String(v) => Ok(v),
+        other => Err(format!(
+            "{}: expected a string but found {:?}",
+            func_name, other
+        )),
+    }
+}
+
+// 値を表示用の文字列にする
+fn value_to_display(value: &VarType) -> String {
+    match value {
+        VarType::Int(v) => v.to_string(),
+        VarType::Float(v) => v.to_string(),
+        VarType::String(v) => v.clone(),
+        VarType::Bool(v) => v.to_string(),
+        VarType::Array(values) => format!(
+            "[{}]",
+            values
+                .iter()
+                .map(value_to_display)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        VarType::Tuple(values) => format!(
+            "({})",
+            values
+                .iter()
+                .map(value_to_display)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        VarType::Object(map) => format!(
+            "{{{}}}",
+            map.iter()
+                .map(|(key, value)| format!("{}: {}", key, value_to_display(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        VarType::Lambda(closure) => format!("<fn({})>", closure.params.join(", ")),
+        VarType::Enum(enum_name, variant) => format!("{}::{}", enum_name, variant),
+        VarType::Null => "null".to_string(),
+    }
+}
+
+// 値をJSONの文字列にする
+fn value_to_json(value: &VarType) -> String {
+    match value {
+        VarType::Int(v) => v.to_string(),
+        // JSONでは有限の数しか表せない
+        VarType::Float(v) if v.is_finite() => format!("{:?}", v),
+        VarType::Float(_) | VarType::Lambda(_) | VarType::Null => "null".to_string(),
+        VarType::String(v) => json_string(v),
+        VarType::Bool(v) => v.to_string(),
+        VarType::Enum(enum_name, variant) => json_string(&format!("{}::{}", enum_name, variant)),
+        // JSONにはタプルがないので配列にする
+        VarType::Array(values) | VarType::Tuple(values) => format!(
+            "[{}]",
+            values
+                .iter()
+                .map(value_to_json)
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+        VarType::Object(map) => format!(
+            "{{{}}}",
+            map.iter()
+                .map(|(key, value)| format!("{}:{}", json_string(key), value_to_json(value)))
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+    }
+}
+
+// 文字列をJSONの文字列リテラルにエスケープする
+fn json_string(string: &str) -> String {
+    let mut json = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// 値を字下げしたJSONの文字列にする
+fn value_to_json_pretty(value: &VarType, indent: usize) -> String {
+    let inner = "  ".repeat(indent + 1);
+    let outer = "  ".repeat(indent);
+    match value {
+        VarType::Array(values) | VarType::Tuple(values) if !values.is_empty() => format!(
+            "[\n{}\n{}]",
+            values
+                .iter()
+                .map(|value| format!("{}{}", inner, value_to_json_pretty(value, indent + 1)))
+                .collect::<Vec<_>>()
+                .join(",\n"),
+            outer
+        ),
+        VarType::Object(map) if !map.is_empty() => format!(
+            "{{\n{}\n{}}}",
+            map.iter()
+                .map(|(key, value)| format!(
+                    "{}{}: {}",
+                    inner,
+                    json_string(key),
+                    value_to_json_pretty(value, indent + 1)
+                ))
+                .collect::<Vec<_>>()
+                .join(",\n"),
+            outer
+        ),
+        _ => value_to_json(value),
+    }
+}
+
+// JSONの文字列を値にする
+fn parse_json(json: &str) -> Result<VarType, String> {
+    let chars: Vec<char> = json.chars().collect();
+    let mut pos = 0;
+    let value = parse_json_value(&chars, &mut pos)?;
+    skip_json_whitespace(&chars, &mut pos);
+    if pos < chars.len() {
+        return Err(json_error(&chars, pos, "trailing characters"));
+    }
+    Ok(value)
+}
+
+fn skip_json_whitespace(chars: &[char], pos: &mut usize) {
+    while chars
+        .get(*pos)
+        .is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r'))
+    {
+        *pos += 1;
+    }
+}
+
+// JSONのエラーに位置を付ける
+fn json_error(chars: &[char], pos: usize, message: &str) -> String {
+    let (line, column) = (
+        chars[..pos].iter().filter(|c| **c == '\n').count() + 1,
+        pos - chars[..pos]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |i| i + 1)
+            + 1,
+    );
+    format!(
+        "parse_json: {} at line {}, column {}",
+        message, line, column
+    )
+}
+
+fn parse_json_value(chars: &[char], pos: &mut usize) -> Result<VarType, String> {
+    skip_json_whitespace(chars, pos);
+    match chars.get(*pos) {
+        Some('{') => {
+            *pos += 1;
+            let mut map = BTreeMap::new();
+            skip_json_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&'}') {
+                *pos += 1;
+                return Ok(VarType::Object(map));
+            }
+            loop {
+                skip_json_whitespace(chars, pos);
+                if chars.get(*pos) != Some(&'"') {
+                    return Err(json_error(chars, *pos, "expected a string key"));
+                }
+                let key = parse_json_string(chars, pos)?;
+                skip_json_whitespace(chars, pos);
+                if chars.get(*pos) != Some(&':') {
+                    return Err(json_error(chars, *pos, "expected ':'"));
+                }
+                *pos += 1;
+                map.insert(key, parse_json_value(chars, pos)?);
+                skip_json_whitespace(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => *pos += 1,
+                    Some('}') => {
+                        *pos += 1;
+                        return Ok(VarType::Object(map));
+                    }
+                    _ => return Err(json_error(chars, *pos, "expected ',' or '}'")),
+                }
+            }
+        }
+        Some('[') => {
+            *pos += 1;
+            let mut values = Vec::new();
+            skip_json_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&']') {
+                *pos += 1;
+                return Ok(VarType::Array(values));
+            }
+            loop {
+                values.push(parse_json_value(chars, pos)?);
+                skip_json_whitespace(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => *pos += 1,
+                    Some(']') => {
+                        *pos += 1;
+                        return Ok(VarType::Array(values));
+                    }
+                    _ => return Err(json_error(chars, *pos, "expected ',' or ']'")),
+                }
+            }
+        }
+        Some('"') => parse_json_string(chars, pos).map(VarType::String),
+        Some(c) if *c == '-' || c.is_ascii_digit() => {
+            let start = *pos;
+            while chars
+                .get(*pos)
+                .is_some_and(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+            {
+                *pos += 1;
+            }
+            let number: String = chars[start..*pos].iter().collect();
+            if let Ok(v) = number.parse::<i64>() {
+                return Ok(VarType::Int(v));
+            }
+            match number.parse::<f64>() {
+                Ok(v) if v.is_finite() => Ok(VarType::Float(v)),
+                _ => Err(json_error(chars, start, "invalid number")),
+            }
+        }
+        _ => {
+            for (word, value) in [
+                ("true", VarType::Bool(true)),
+                ("false", VarType::Bool(false)),
+                ("null", VarType::Null),
+            ] {
+                if chars[*pos..].starts_with(&word.chars().collect::<Vec<_>>()) {
+                    *pos += word.len();
+                    return Ok(value);
+                }
+            }
+            Err(json_error(chars, *pos, "unexpected character"))
+        }
+    }
+}
+
+// "から始まるJSONの文字列を読む
+fn parse_json_string(chars: &[char], pos: &mut usize) -> Result<String, String> {
+    let start = *pos;
+    let mut string = String::new();
+    *pos += 1;
+    loop {
+        let c = match chars.get(*pos) {
+            Some(c) => *c,
+            None => return Err(json_error(chars, start, "unterminated string")),
+        };
+        *pos += 1;
+        match c {
+            '"' => return Ok(string),
+            '\\' => {
+                let escaped = chars.get(*pos).copied();
+                *pos += 1;
+                match escaped {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('/') => string.push('/'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('u') => {
+                        let mut code = parse_json_hex(chars, pos)?;
+                        // サロゲートペアは二つ合わせて一文字にする
+                        if (0xd800..0xdc00).contains(&code)
+                            && chars.get(*pos) == Some(&'\\')
+                            && chars.get(*pos + 1) == Some(&'u')
+                        {
+                            *pos += 2;
+                            let low = parse_json_hex(chars, pos)?;
+                            code = 0x10000
+                                + ((code - 0xd800) << 10)
+                                + (low.wrapping_sub(0xdc00) & 0x3ff);
+                        }
+                        string.push(char::from_u32(code).ok_or_else(|| {
+                            json_error(chars, *pos - 4, "invalid unicode escape")
+                        })?);
+                    }
+                    _ => return Err(json_error(chars, *pos - 2, "invalid escape")),
+                }
+            }
+            c => string.push(c),
+        }
+    }
+}
+
+fn parse_json_hex(chars: &[char], pos: &mut usize) -> Result<u32, String> {
+    let hex: String = chars.iter().skip(*pos).take(4).collect();
+    let code = u32::from_str_radix(&hex, 16)
+        .ok()
+        .filter(|_| hex.len() == 4)
+        .ok_or_else(|| json_error(chars, *pos, "invalid unicode escape"))?;
+    *pos += 4;
+    Ok(code)
+}
+
+// 値の実行時の型名を返す
+fn infer_type(value: &VarType) -> &'static str {
+    match value {
+        VarType::Int(_) => "i32",
+        VarType::Float(_) => "f64",
+        VarType::String(_) => "string",
+        VarType::Bool(_) => "bool",
+        VarType::Array(_) => "array",
+        VarType::Object(_) => "object",
+        VarType::Tuple(_) => "tuple",
+        VarType::Lambda(_) => "function",
+        VarType::Enum(..) => "enum",
+        VarType::Null => "void",
+    }
+}
+
+// 添字で要素を読む (マップにないキーはNull)
+fn index_value(target: &VarType, index: &VarType) -> Result<VarType, String> {
+    match (target, index) {
+        (VarType::Object(map), VarType::String(key)) => {
+            Ok(map.get(key).cloned().unwrap_or(VarType::Null))
+        }
+        (VarType::Array(values), VarType::Int(i)) => {
+            Ok(values[array_index(values.len(), *i)?].clone())
+        }
+        _ => Err(format!("Cannot index {:?} with {:?}", target, index)),
+    }
+}
+
+// 配列の添字を範囲内の位置にする (負の添字は末尾から数える)
+fn array_index(len: usize, index: i64) -> Result<usize, String> {
+    let position = if index < 0 {
+        i64::try_from(len).ok().map(|len| len + index)
+    } else {
+        Some(index)
+    };
+    position
+        .and_then(|i| usize::try_from(i).ok())
+        .filter(|i| *i < len)
+        .ok_or_else(|| format!("index out of bounds: {} (length {})", index, len))
+}
+
+// 添字の位置に値を書き込む
+fn set_index(target: &mut VarType, index: &VarType, value: VarType) -> Result<(), String> {
+    match (target, index) {
+        (VarType::Object(map), VarType::String(key)) => {
+            map.insert(key.clone(), value);
+            Ok(())
+        }
+        (VarType::Array(values), VarType::Int(i)) => {
+            let i = array_index(values.len(), *i)?;
+            values[i] = value;
+            Ok(())
+        }
+        (target, _) => Err(format!("Cannot index {:?} with {:?}", target, index)),
+    }
+}
+
+// マップの引数を取り出す
+fn to_map<'v>(
+    func_name: &str,
+    value: &'v VarType,
+) -> Result<&'v BTreeMap<String, VarType>, String> {
+    match value {
+        VarType::Object(map) => Ok(map),
+        other => Err(format!(
+            "{}: expected a map but found {:?}",
+            func_name, other
+        )),
+    }
+}
+
+// {}と{:?}を引数で順に置き換える ({:?}はJSON形式、{{と}}はそのまま{と})
+fn format_string(func_name: &str, fmt: &str, args: &[VarType]) -> Result<String, String> {
+    let mut formatted = String::new();
+    let mut args = args.iter();
+    let mut rest = fmt;
+    while let Some(i) = rest.find(['{', '}']) {
+        formatted.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let (replacement, len) = if rest.starts_with("{{") {
+            ("{".to_string(), 2)
+        } else if rest.starts_with("}}") {
+            ("}".to_string(), 2)
+        } else if rest.starts_with("{}") || rest.starts_with("{:?}") {
+            let arg = args.next().ok_or_else(|| {
+                format!(
+                    "{}: more placeholders than arguments in {:?}",
+                    func_name, fmt
+                )
+            })?;
+            if rest.starts_with("{}") {
+                (value_to_display(arg), 2)
+            } else {
+                (value_to_json(arg), 4)
+            }
+        } else {
+            return Err(format!("{}: invalid format string {:?}", func_name, fmt));
+        };
+        formatted.push_str(&replacement);
+        rest = &rest[len..];
+    }
+    formatted.push_str(rest);
+    Ok(formatted)
+}
+
+// 配列の引数を取り出す
+fn to_array<'v>(func_name: &str, value: &'v VarType) -> Result<&'v [VarType], String> {
+    match value {
+        VarType::Array(values) => Ok(values),
+        other => Err(format!(
+            "{}: expected an array but found {:?}",
+            func_name, other
+        )),
+    }
+}
+
+// 整数の引数を取り出す
+fn to_i64(func_name: &str, value: &VarType) -> Result<i64, String> {
+    match value {
+        VarType::Int(v) => Ok(*v),
+        other => Err(format!(
+            "{}: expected an integer but found {:?}",
+            func_name, other
+        )),
+    }
+}
+
+// 数値の引数をf64として取り出す
+fn to_f64(func_name: &str, value: &VarType) -> Result<f64, String> {
+    match value {
+        VarType::Int(v) => Ok(*v as f64),
+        VarType::Float(v) => Ok(*v),
+        other => Err(format!(
+            "{}: expected a number but found {:?}",
+            func_name, other
+        )),
+    }
+}
+
+pub struct ScopeManager {
+    pub scopes: Vec<HashMap<String, VarType>>, // スコープ毎の変数名と値の関連付けを管理するVec
+    pub constants: Vec<HashSet<String>>,       // スコープ毎の定数名
+    pub enums: HashMap<String, Vec<String>>,   // 列挙型の名前と列挙子
+}
+
+impl Default for ScopeManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScopeManager {
+    pub fn new() -> ScopeManager {
+        ScopeManager {
+            scopes: vec![HashMap::new()],
+            constants: vec![HashSet::new()],
+            enums: HashMap::new(),
+        } // 初期スコープを作成
+    }
+
+    pub fn create_scope(&mut self) {
+        self.scopes.push(HashMap::new()); // 新しいスコープを作成して追加
+        self.constants.push(HashSet::new());
+    }
+
+    pub fn destroy_scope(&mut self) {
         self.scopes.pop(); // 最後のスコープを削除
+        self.constants.pop();
     }
 
-    fn set_variable(&mut self, name: String, value: VarType) -> Result<(), String> {
+    pub fn set_variable(&mut self, name: String, value: VarType) -> Result<(), String> {
         if let Some(scope) = self.scopes.last_mut() {
+            if let Some(constants) = self.constants.last_mut() {
+                constants.remove(&name); // letで宣言し直したら定数ではなくなる
+            }
             scope.insert(name, value); // 現在のスコープに変数を追加
             Ok(())
         } else {
@@ -274,7 +1724,30 @@ impl ScopeManager {
         }
     }
 
-    fn get_variable(&self, name: &str) -> Option<VarType> {
+    // 現在のスコープに書き換えできない変数を追加
+    pub fn set_constant(&mut self, name: String, value: VarType) -> Result<(), String> {
+        self.set_variable(name.clone(), value)?;
+        if let Some(constants) = self.constants.last_mut() {
+            constants.insert(name);
+        }
+        Ok(())
+    }
+
+    // 変数が定義されている一番内側のスコープの値を書き換える
+    pub fn assign_variable(&mut self, name: &str, value: VarType) -> Result<(), String> {
+        for (scope, constants) in self.scopes.iter_mut().zip(&self.constants).rev() {
+            if let Some(slot) = scope.get_mut(name) {
+                if constants.contains(name) {
+                    return Err(format!("cannot assign to constant: {}", name));
+                }
+                *slot = value;
+                return Ok(());
+            }
+        }
+        Err(format!("Variable not found: {}", name))
+    }
+
+    pub fn get_variable(&self, name: &str) -> Option<VarType> {
         for scope in self.scopes.iter().rev() {
             if let Some(value) = scope.get(name) {
                 return Some(value.clone()); // スコープから変数を取
@@ -284,38 +1757,896 @@ impl ScopeManager {
     }
 }
 
+// 関数の呼び出し中に退避しておくスコープ毎の変数と定数名
+type CallerScopes = (Vec<HashMap<String, VarType>>, Vec<HashSet<String>>);
+
 pub struct Decoder<'a> {
-    pub parser: &'a Parser<'a>,
     pub scope_manager: &'a mut ScopeManager,
+    strict_variables: bool, // 未定義の変数の読み込みをエラーにするか
+    int_division: bool,     // 整数同士の/を切り捨てにするか
+    optimize: bool,         // 実行前に定数を埋め込むか
+    rng_state: u64,         // @randomのxorshiftの状態
+    source: Option<String>, // エラー表示に使うソース
+    error_position: Option<(usize, usize)>, // 最初にエラーになったノードの位置
+    exit_code: Option<i32>, // @exitで渡された終了コード
+    caller_scopes: Vec<CallerScopes>, // 関数を呼び出した側のスコープ (長さが呼び出しの深さ)
+    max_call_depth: usize,  // 関数呼び出しの深さの上限
 }
 
 impl<'a> Decoder<'a> {
-    pub fn new(parser: &'a Parser, scope_manager: &'a mut ScopeManager) -> Decoder<'a> {
+    pub fn new(scope_manager: &'a mut ScopeManager) -> Decoder<'a> {
         Decoder {
-            parser,
             scope_manager,
+            strict_variables: true,
+            int_division: true,
+            optimize: false,
+            // 乱数の種は現在時刻 (xorshiftは0だと動かない)
+            rng_state: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64)
+                | 1,
+            source: None,
+            error_position: None,
+            exit_code: None,
+            caller_scopes: Vec::new(),
+            max_call_depth: 64,
         }
     }
 
-    pub fn decode(&mut self, program: &Vec<Box<Node>>) -> Result<(), String> {
-        // 今回は単純に宣言された変数のリストを表示
-        for (index, node) in program.iter().enumerate() {
-            match &node.node_type {
-                NodeType::VarDecl => {
-                    if let Some(node) = node.child.get(0) {
-                        if let NodeType::Var(name) = &node.node_type {
-                            let var_name = name;
-                            let result = self.parser.eval(node)?;
-                            self.scope_manager.set_variable(var_name.clone(), result)?;
+    // エラーにソースの該当行を表示できるようにする
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.replace("\r\n", "\n").replace('\r', "\n"));
+        self
+    }
+
+    // falseにすると未定義の変数はNullとして読まれる
+    pub fn strict_variables(mut self, strict: bool) -> Self {
+        self.strict_variables = strict;
+        self
+    }
+
+    // falseにすると整数同士の/の結果が浮動小数点数になる
+    pub fn int_division(mut self, truncate: bool) -> Self {
+        self.int_division = truncate;
+        self
+    }
+
+    // 関数呼び出しの深さの上限を変える (ホストのスタックあふれを防ぐ)
+    pub fn max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self
+    }
+
+    // 乱数の種を固定する
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng_state = seed.max(1);
+        self
+    }
+
+    // xorshift64で次の乱数を作る
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+        x
+    }
+
+    // trueにすると実行前にpropagate_constantsを適用する
+    pub fn optimize(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    // ホスト側の値をグローバル変数として渡す
+    pub fn set_global(&mut self, name: &str, value: impl Into<VarType>) {
+        self.scope_manager.scopes[0].insert(name.to_string(), value.into());
+    }
+
+    // グローバル変数をホスト側の型で取り出す
+    pub fn get_global<T: TryFrom<VarType, Error = String>>(&self, name: &str) -> Result<T, String> {
+        match self.scope_manager.scopes[0].get(name) {
+            Some(value) => T::try_from(value.clone()),
+            None => Err(format!("Variable not found: {}", name)),
+        }
+    }
+
+    // @exitで実行が止められたときの終了コード
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // スクリプトと同じ規則で二項演算を行う
+    pub fn apply_op(op: &str, a: &VarType, b: &VarType) -> Result<VarType, String> {
+        match op {
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => eval_binary_condition(op, a, b),
+            _ => eval_binary_op(op, a, b, true),
+        }
+    }
+
+    // グローバル変数の名前と値をJSONにする (JSONで表せない関数は含めない)
+    pub fn globals_json(&self) -> String {
+        let mut globals: Vec<_> = match self.scope_manager.scopes.first() {
+            Some(scope) => scope
+                .iter()
+                .filter(|(_, value)| !matches!(value, VarType::Lambda(_)))
+                .collect(),
+            None => Vec::new(),
+        };
+        globals.sort_by(|a, b| a.0.cmp(b.0));
+        let entries: Vec<String> = globals
+            .iter()
+            .map(|(name, value)| format!("{}:{}", json_string(name), value_to_json(value)))
+            .collect();
+        format!("{{{}}}", entries.join(","))
+    }
+
+    pub fn decode(&mut self, program: &[Box<Node>]) -> Result<(), String> {
+        let optimized;
+        self.exit_code = None;
+        let program = if self.optimize {
+            optimized = propagate_constants(program);
+            &optimized[..]
+        } else {
+            program
+        };
+        // 文を順番に実行し、宣言された変数を表示
+        for node in program {
+            self.error_position = None;
+            if let Err(e) = self.eval(node) {
+                // @exitはエラーではなく実行の終わりとして扱う (メッセージでは判断しない)
+                if self.exit_code.is_some() {
+                    return Ok(());
+                }
+                return Err(self.format_error(e));
+            }
+            let node: &Node = match node.node_type {
+                NodeType::Global => &node.child[0],
+                _ => node,
+            };
+            let decls = match node.node_type {
+                NodeType::VarDecl | NodeType::ConstDecl => std::slice::from_ref(node),
+                NodeType::MultiDecl => &node.child[..],
+                _ => &[],
+            };
+            for decl in decls {
+                if let Some(value) = self.scope_manager.get_variable(&decl.value) {
+                    println!("var name: {:?}  value: {:?}", decl.value, value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // REPLの一行を実行し、最後の値とそれが文だったかを返す
+    pub fn eval_line(&mut self, src: &str) -> Result<(VarType, bool), String> {
+        let tokens = Tokenizer::new().tokenize(src)?;
+        let mut parser = Parser::new(&tokens);
+        let nodes = program(&mut parser)?;
+        self.source = Some(src.replace("\r\n", "\n").replace('\r', "\n"));
+        self.exit_code = None;
+        let mut result = (VarType::Null, true);
+        for node in &nodes {
+            self.error_position = None;
+            match self.eval(node) {
+                Ok(value) => result = (value, node.is_statement()),
+                Err(_) if self.exit_code.is_some() => return Ok((VarType::Null, true)),
+                Err(e) => return Err(self.format_error(e)),
+            }
+        }
+        Ok(result)
+    }
+
+    // ソースを実行して最後の値を返す
+    pub fn eval_str(&mut self, source: &str) -> Result<VarType, String> {
+        self.eval_line(source).map(|(value, _)| value)
+    }
+
+    // エラーに位置とソースの該当行を付け加える
+    fn format_error(&self, message: String) -> String {
+        let (line, column) = match self.error_position {
+            Some(position) => position,
+            None => return message,
+        };
+        let mut formatted = format!("{} (line {}, column {})", message, line, column);
+        let source_line = self
+            .source
+            .as_ref()
+            .and_then(|source| source.trim_start_matches('\u{feff}').lines().nth(line - 1));
+        if let Some(source_line) = source_line {
+            formatted.push_str(&format!("\n{}\n{}^", source_line, " ".repeat(column - 1)));
+        }
+        formatted
+    }
+
+    // ノードを評価して値を返す
+    pub fn eval(&mut self, node: &Node) -> Result<VarType, String> {
+        let result = self.eval_node(node);
+        // 一番内側のエラーになったノードの位置を覚えておく
+        if result.is_err() && self.error_position.is_none() && node.line > 0 {
+            self.error_position = Some((node.line, node.column));
+        }
+        result
+    }
+
+    fn eval_node(&mut self, node: &Node) -> Result<VarType, String> {
+        match &node.node_type {
+            NodeType::Add
+            | NodeType::Sub
+            | NodeType::Mul
+            | NodeType::Div
+            | NodeType::Mod
+            | NodeType::Pow => {
+                let left = self.eval(&node.child[0])?;
+                let right = self.eval(&node.child[1])?;
+                eval_binary_op(&node.value, &left, &right, self.int_division)
+            }
+            NodeType::Eq
+            | NodeType::Ne
+            | NodeType::Lt
+            | NodeType::Gt
+            | NodeType::Le
+            | NodeType::Ge => {
+                let left = self.eval(&node.child[0])?;
+                let right = self.eval(&node.child[1])?;
+                eval_binary_condition(&node.value, &left, &right)
+            }
+            NodeType::And | NodeType::Or => {
+                // 左辺だけで結果が決まるときは右辺を評価しない
+                let left = to_bool(&node.value, &self.eval(&node.child[0])?)?;
+                if left == (node.node_type == NodeType::Or) {
+                    return Ok(VarType::Bool(left));
+                }
+                let right = to_bool(&node.value, &self.eval(&node.child[1])?)?;
+                Ok(VarType::Bool(right))
+            }
+            // -9223372036854775808 だけは符号を付けるとi64に収まる
+            NodeType::Neg
+                if matches!(
+                    &node.child[0].node_type,
+                    NodeType::Num(n) if n == "9223372036854775808"
+                ) =>
+            {
+                Ok(VarType::Int(i64::MIN))
+            }
+            NodeType::Neg => match self.eval(&node.child[0])? {
+                VarType::Int(v) => v
+                    .checked_neg()
+                    .map(VarType::Int)
+                    .ok_or_else(|| format!("Integer overflow: -{}", v)),
+                VarType::Float(v) => Ok(VarType::Float(-v)),
+                other => Err(format!("Unsupported operand type for -: {:?}", other)),
+            },
+            NodeType::Not => Ok(VarType::Bool(!to_bool(
+                &node.value,
+                &self.eval(&node.child[0])?,
+            )?)),
+            NodeType::PostInc | NodeType::PostDec => {
+                // 変数を更新し、更新前の値を返す
+                let name = &node.child[0].value;
+                let old = self.eval(&node.child[0])?;
+                let op = if node.node_type == NodeType::PostInc {
+                    "+"
+                } else {
+                    "-"
+                };
+                let new = eval_binary_op(op, &old, &VarType::Int(1), self.int_division)?;
+                self.scope_manager.assign_variable(name, new)?;
+                Ok(old)
+            }
+            NodeType::Bool(value) => Ok(VarType::Bool(*value)),
+            NodeType::Map => {
+                let mut map = BTreeMap::new();
+                for entry in node.child.chunks(2) {
+                    let value = self.eval(&entry[1])?;
+                    map.insert(entry[0].value.clone(), value);
+                }
+                Ok(VarType::Object(map))
+            }
+            NodeType::Tuple | NodeType::Array => {
+                let mut values = Vec::new();
+                for element in &node.child {
+                    values.push(self.eval(element)?);
+                }
+                if node.node_type == NodeType::Tuple {
+                    Ok(VarType::Tuple(values))
+                } else {
+                    Ok(VarType::Array(values))
+                }
+            }
+            NodeType::TupleField => match self.eval(&node.child[0])? {
+                VarType::Tuple(values) => node
+                    .value
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| values.get(index).cloned())
+                    .ok_or_else(|| {
+                        format!(
+                            "Tuple index {} out of range for a tuple of length {}",
+                            node.value,
+                            values.len()
+                        )
+                    }),
+                other => Err(format!(
+                    "Cannot access field .{} of {:?}",
+                    node.value, other
+                )),
+            },
+            NodeType::Index => {
+                let target = self.eval(&node.child[0])?;
+                let index = self.eval(&node.child[1])?;
+                index_value(&target, &index)
+            }
+            NodeType::IndexAssign => {
+                let mut target = self.eval(&Node::new(
+                    NodeType::Var(node.value.clone()),
+                    vec![],
+                    node.value.clone(),
+                ))?;
+                let index = self.eval(&node.child[0])?;
+                let value = self.eval(&node.child[1])?;
+                set_index(&mut target, &index, value)?;
+                self.scope_manager.assign_variable(&node.value, target)?;
+                Ok(VarType::Null)
+            }
+
+            // 他のパターンもVarTypeに応じて返り値の型を変更します
+            NodeType::Var(variable_name) => {
+                if let Some(value) = self.scope_manager.get_variable(variable_name) {
+                    Ok(value) // 変数名に対応する値を返す
+                } else if self.strict_variables {
+                    Err(format!("Variable not found: {}", variable_name))
+                } else {
+                    Ok(VarType::Null)
+                }
+            }
+            NodeType::VarDecl => {
+                let value = self.eval(&node.child[0])?;
+                self.scope_manager.set_variable(node.value.clone(), value)?;
+                Ok(VarType::Null)
+            }
+            NodeType::ConstDecl => {
+                let value = self.eval(&node.child[0])?;
+                self.scope_manager.set_constant(node.value.clone(), value)?;
+                Ok(VarType::Null)
+            }
+            NodeType::Global => {
+                // 右辺は今のスコープで評価し、宣言した変数を一番外側のスコープに移す
+                self.scope_manager.create_scope();
+                let result = self.eval(&node.child[0]);
+                let scope = self.scope_manager.scopes.pop().unwrap_or_default();
+                let constants = self.scope_manager.constants.pop().unwrap_or_default();
+                result?;
+                // 関数の中では一番外側が取り込んだ変数なので、呼び出し元すべての一番外側にも宣言する
+                let mut outermost = vec![(
+                    &mut self.scope_manager.scopes[0],
+                    &mut self.scope_manager.constants[0],
+                )];
+                for (scopes, scope_constants) in &mut self.caller_scopes {
+                    outermost.push((&mut scopes[0], &mut scope_constants[0]));
+                }
+                for (outer_scope, outer_constants) in outermost {
+                    for (name, value) in &scope {
+                        if constants.contains(name) {
+                            outer_constants.insert(name.clone());
+                        } else {
+                            outer_constants.remove(name);
+                        }
+                        outer_scope.insert(name.clone(), value.clone());
+                    }
+                }
+                Ok(VarType::Null)
+            }
+            NodeType::MultiDecl => {
+                // 右辺をすべて評価してから宣言する
+                let mut values = Vec::new();
+                for decl in &node.child {
+                    values.push(self.eval(&decl.child[0])?);
+                }
+                for (decl, value) in node.child.iter().zip(values) {
+                    if decl.node_type == NodeType::ConstDecl {
+                        self.scope_manager.set_constant(decl.value.clone(), value)?;
+                    } else {
+                        self.scope_manager.set_variable(decl.value.clone(), value)?;
+                    }
+                }
+                Ok(VarType::Null)
+            }
+            NodeType::TupleAssign => {
+                let (targets, value) = node.child.split_at(node.child.len() - 1);
+                let values = match self.eval(&value[0])? {
+                    VarType::Tuple(values) | VarType::Array(values) => values,
+                    other => {
+                        return Err(format!(
+                            "Cannot destructure {:?} into ({})",
+                            other, node.value
+                        ))
+                    }
+                };
+                if values.len() != targets.len() {
+                    return Err(format!(
+                        "Expected {} values for ({}) but found {}",
+                        targets.len(),
+                        node.value,
+                        values.len()
+                    ));
+                }
+                for (target, value) in targets.iter().zip(values) {
+                    self.scope_manager.assign_variable(&target.value, value)?;
+                }
+                Ok(VarType::Null)
+            }
+            NodeType::VarAssign => {
+                let value = self.eval(&node.child[0])?;
+                self.scope_manager.assign_variable(&node.value, value)?;
+                Ok(VarType::Null)
+            }
+            NodeType::Block(has_tail) => {
+                self.scope_manager.create_scope();
+                let mut result = Ok(VarType::Null);
+                for statement in &node.child {
+                    result = self.eval(statement);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                self.scope_manager.destroy_scope();
+                let value = result?;
+                Ok(if *has_tail { value } else { VarType::Null })
+            }
+            NodeType::Match => {
+                // 値は一度だけ評価し、最初に一致した腕を実行する
+                let value = self.eval(&node.child[0])?;
+                for arm in node.child[1..].chunks(2) {
+                    if arm[0].node_type == NodeType::Wildcard || self.eval(&arm[0])? == value {
+                        return self.eval(&arm[1]);
+                    }
+                }
+                Ok(VarType::Null)
+            }
+            NodeType::Num(expression) => match expression.parse::<VarType>() {
+                // 整数のリテラルは浮動小数点数にせずエラーにする
+                Ok(VarType::Float(_)) if expression.chars().all(|c| c.is_ascii_digit()) => {
+                    Err(format!("Integer literal out of range: {}", expression))
+                }
+                Ok(VarType::Float(v)) if !v.is_finite() => {
+                    Err(format!("Number is out of range: {}", expression))
+                }
+                Ok(value) => Ok(value),
+                Err(_) => Err("Invalid expression".to_string()),
+            },
+            NodeType::Str(string) => Ok(VarType::String(string.clone())),
+            // 文字は長さ1の文字列として扱う
+            NodeType::Char(ch) => Ok(VarType::String(ch.to_string())),
+            NodeType::Call(func_name) => {
+                let mut args = Vec::new();
+                for arg in &node.child {
+                    args.push(self.eval(arg)?);
+                }
+                // 関数を持つ変数があればシステム関数より優先する
+                if let Some(VarType::Lambda(closure)) = self.scope_manager.get_variable(func_name) {
+                    return self.call_closure(func_name, &closure, args);
+                }
+                self.eval_call(func_name, &args)
+            }
+            NodeType::Interpolation => {
+                let value = self.eval(&node.child[0])?;
+                self.eval_call("str", &[value])
+            }
+            NodeType::EnumDecl(variants) => {
+                if self.scope_manager.enums.contains_key(&node.value) {
+                    return Err(format!("Enum already declared: {}", node.value));
+                }
+                self.scope_manager
+                    .enums
+                    .insert(node.value.clone(), variants.clone());
+                Ok(VarType::Null)
+            }
+            NodeType::EnumVariant(enum_name) => match self.scope_manager.enums.get(enum_name) {
+                Some(variants) if variants.contains(&node.value) => {
+                    Ok(VarType::Enum(enum_name.clone(), node.value.clone()))
+                }
+                Some(_) => Err(format!("Unknown variant: {}::{}", enum_name, node.value)),
+                None => Err(format!("Unknown enum: {}", enum_name)),
+            },
+            NodeType::Lambda(params) => {
+                // 外側から見える変数を内側のものを優先して複製する
+                let mut captured = HashMap::new();
+                let mut constants = HashSet::new();
+                for (scope, scope_constants) in self
+                    .scope_manager
+                    .scopes
+                    .iter()
+                    .zip(&self.scope_manager.constants)
+                {
+                    for (name, value) in scope {
+                        if scope_constants.contains(name) {
+                            constants.insert(name.clone());
+                        } else {
+                            constants.remove(name);
                         }
+                        captured.insert(name.clone(), value.clone());
                     }
                 }
-                _ => (),
+                Ok(VarType::Lambda(Box::new(Closure {
+                    params: params.clone(),
+                    body: node.child[0].clone(),
+                    captured,
+                    constants,
+                })))
             }
-            self.parser.print_var(node, index)?;
+            _ => Err("Invalid operation".to_string()),
         }
+    }
 
-        Ok(())
+    // 複製した変数と引数だけが見えるスコープで関数の本体を評価する
+    fn call_closure(
+        &mut self,
+        func_name: &str,
+        closure: &Closure,
+        args: Vec<VarType>,
+    ) -> Result<VarType, String> {
+        check_arg_count(func_name, &args, closure.params.len())?;
+        if self.caller_scopes.len() >= self.max_call_depth {
+            return Err(format!(
+                "Maximum call depth exceeded: {} ({})",
+                func_name, self.max_call_depth
+            ));
+        }
+        let locals = closure.params.iter().cloned().zip(args).collect();
+        let scopes = std::mem::replace(
+            &mut self.scope_manager.scopes,
+            vec![closure.captured.clone(), locals],
+        );
+        let constants = std::mem::replace(
+            &mut self.scope_manager.constants,
+            vec![closure.constants.clone(), HashSet::new()],
+        );
+        self.caller_scopes.push((scopes, constants));
+        let result = self.eval(&closure.body);
+        if let Some((scopes, constants)) = self.caller_scopes.pop() {
+            self.scope_manager.scopes = scopes;
+            self.scope_manager.constants = constants;
+        }
+        result
+    }
+
+    // システム関数を呼び出す
+    fn eval_call(&mut self, func_name: &str, args: &[VarType]) -> Result<VarType, String> {
+        match func_name {
+            "abs" => {
+                check_arg_count(func_name, args, 1)?;
+                match &args[0] {
+                    VarType::Int(v) => v
+                        .checked_abs()
+                        .map(VarType::Int)
+                        .ok_or_else(|| format!("abs: overflow for {}", v)),
+                    VarType::Float(v) => Ok(VarType::Float(v.abs())),
+                    other => Err(format!("abs: expected a number but found {:?}", other)),
+                }
+            }
+            "sqrt" => {
+                check_arg_count(func_name, args, 1)?;
+                let value = to_f64(func_name, &args[0])?;
+                if value < 0.0 {
+                    return Err(format!("sqrt: negative argument {}", value));
+                }
+                Ok(VarType::Float(value.sqrt()))
+            }
+            "floor" | "ceil" | "round" | "trunc" => {
+                check_arg_count(func_name, args, 1)?;
+                let value = match &args[0] {
+                    VarType::Int(v) => return Ok(VarType::Int(*v)),
+                    VarType::Float(v) => *v,
+                    other => {
+                        return Err(format!(
+                            "{}: expected a number but found {:?}",
+                            func_name, other
+                        ))
+                    }
+                };
+                let rounded = match func_name {
+                    "floor" => value.floor(),
+                    "ceil" => value.ceil(),
+                    "round" => value.round(),
+                    _ => value.trunc(),
+                };
+                // i64に収まるなら整数にする
+                if rounded >= i64::MIN as f64 && rounded < i64::MAX as f64 {
+                    Ok(VarType::Int(rounded as i64))
+                } else {
+                    Ok(VarType::Float(rounded))
+                }
+            }
+            "min" | "max" => {
+                check_arg_count(func_name, args, 2)?;
+                let is_min = func_name == "min";
+                match (&args[0], &args[1]) {
+                    (VarType::Int(l), VarType::Int(r)) => {
+                        Ok(VarType::Int(if is_min { *l.min(r) } else { *l.max(r) }))
+                    }
+                    // 片方が浮動小数点数なら結果も浮動小数点数にそろえる
+                    (l, r) => {
+                        let (l, r) = (to_f64(func_name, l)?, to_f64(func_name, r)?);
+                        Ok(VarType::Float(if is_min { l.min(r) } else { l.max(r) }))
+                    }
+                }
+            }
+            "to_int" => {
+                check_arg_count(func_name, args, 1)?;
+                let string = to_str(func_name, &args[0])?;
+                i64::from_str(string.trim())
+                    .map(VarType::Int)
+                    .map_err(|e| format!("to_int: cannot parse {:?} as integer: {}", string, e))
+            }
+            "to_float" => {
+                check_arg_count(func_name, args, 1)?;
+                let string = to_str(func_name, &args[0])?;
+                // NaNや無限大になる文字列は受け付けない
+                match f64::from_str(string.trim()) {
+                    Ok(v) if v.is_finite() => Ok(VarType::Float(v)),
+                    Ok(_) => Err(format!("to_float: {:?} is not a finite number", string)),
+                    Err(e) => Err(format!(
+                        "to_float: cannot parse {:?} as float: {}",
+                        string, e
+                    )),
+                }
+            }
+            "to_upper" => {
+                check_arg_count(func_name, args, 1)?;
+                Ok(VarType::String(to_str(func_name, &args[0])?.to_uppercase()))
+            }
+            "to_lower" => {
+                check_arg_count(func_name, args, 1)?;
+                Ok(VarType::String(to_str(func_name, &args[0])?.to_lowercase()))
+            }
+            "trim" => {
+                check_arg_count(func_name, args, 1)?;
+                Ok(VarType::String(
+                    to_str(func_name, &args[0])?.trim().to_string(),
+                ))
+            }
+            "split" => {
+                check_arg_count(func_name, args, 2)?;
+                let string = to_str(func_name, &args[0])?;
+                let sep = to_str(func_name, &args[1])?;
+                // 区切り文字が空なら一文字ずつに分割する
+                let parts: Vec<VarType> = if sep.is_empty() {
+                    string
+                        .chars()
+                        .map(|c| VarType::String(c.to_string()))
+                        .collect()
+                } else {
+                    string
+                        .split(sep)
+                        .map(|part| VarType::String(part.to_string()))
+                        .collect()
+                };
+                Ok(VarType::Array(parts))
+            }
+            "contains" => {
+                check_arg_count(func_name, args, 2)?;
+                // 配列なら要素に含まれるかを調べる
+                if let VarType::Array(values) = &args[0] {
+                    return Ok(VarType::Bool(values.contains(&args[1])));
+                }
+                let string = to_str(func_name, &args[0])?;
+                Ok(VarType::Bool(string.contains(to_str(func_name, &args[1])?)))
+            }
+            "starts_with" | "ends_with" => {
+                check_arg_count(func_name, args, 2)?;
+                let string = to_str(func_name, &args[0])?;
+                let affix = to_str(func_name, &args[1])?;
+                Ok(VarType::Bool(if func_name == "starts_with" {
+                    string.starts_with(affix)
+                } else {
+                    string.ends_with(affix)
+                }))
+            }
+            "join" => {
+                check_arg_count(func_name, args, 2)?;
+                let values = to_array(func_name, &args[0])?;
+                let sep = to_str(func_name, &args[1])?;
+                let mut parts = Vec::new();
+                for (i, value) in values.iter().enumerate() {
+                    match value {
+                        VarType::String(part) => parts.push(part.as_str()),
+                        other => {
+                            return Err(format!("join: element {} is not a string: {:?}", i, other))
+                        }
+                    }
+                }
+                Ok(VarType::String(parts.join(sep)))
+            }
+            "sort" => {
+                if args.is_empty() || args.len() > 2 {
+                    return Err(format!(
+                        "sort: expected 1 or 2 argument(s) but got {}",
+                        args.len()
+                    ));
+                }
+                let mut values = to_array(func_name, &args[0])?.to_vec();
+                let descending = match args.get(1) {
+                    Some(value) => to_bool(func_name, value)?,
+                    None => false,
+                };
+                // 数値同士か文字列同士でなければ並べられない
+                let all_numbers = values
+                    .iter()
+                    .all(|v| matches!(v, VarType::Int(_) | VarType::Float(_)));
+                let all_strings = values.iter().all(|v| matches!(v, VarType::String(_)));
+                if !all_numbers && !all_strings {
+                    return Err(format!("sort: cannot sort mixed values {:?}", values));
+                }
+                values.sort_by(|a, b| match (a, b) {
+                    (VarType::Int(a), VarType::Int(b)) => a.cmp(b),
+                    (VarType::String(a), VarType::String(b)) => a.cmp(b),
+                    (a, b) => {
+                        let (a, b) = (to_f64(func_name, a), to_f64(func_name, b));
+                        a.unwrap_or(0.0).total_cmp(&b.unwrap_or(0.0))
+                    }
+                });
+                if descending {
+                    values.reverse();
+                }
+                Ok(VarType::Array(values))
+            }
+            "reverse" => {
+                check_arg_count(func_name, args, 1)?;
+                let mut values = to_array(func_name, &args[0])?.to_vec();
+                values.reverse();
+                Ok(VarType::Array(values))
+            }
+            "str" => {
+                check_arg_count(func_name, args, 1)?;
+                Ok(VarType::String(value_to_display(&args[0])))
+            }
+            "keys" => {
+                check_arg_count(func_name, args, 1)?;
+                let map = to_map(func_name, &args[0])?;
+                Ok(VarType::Array(
+                    map.keys().map(|key| VarType::String(key.clone())).collect(),
+                ))
+            }
+            "values" => {
+                check_arg_count(func_name, args, 1)?;
+                let map = to_map(func_name, &args[0])?;
+                Ok(VarType::Array(map.values().cloned().collect()))
+            }
+            "slice" => {
+                check_arg_count(func_name, args, 3)?;
+                let values = to_array(func_name, &args[0])?;
+                let (start, end) = (to_i64(func_name, &args[1])?, to_i64(func_name, &args[2])?);
+                if start > end {
+                    return Err(format!(
+                        "slice: start {} is greater than end {}",
+                        start, end
+                    ));
+                }
+                // 範囲外の位置は配列の端にそろえる
+                let clamp = |i: i64| {
+                    usize::try_from(i.max(0))
+                        .unwrap_or(usize::MAX)
+                        .min(values.len())
+                };
+                Ok(VarType::Array(values[clamp(start)..clamp(end)].to_vec()))
+            }
+            "println" => {
+                // 文字列は引用符なしで、複数の値は空白で区切って表示する
+                let line: Vec<String> = args.iter().map(value_to_display).collect();
+                println!("{}", line.join(" "));
+                Ok(VarType::Null)
+            }
+            "print" | "format" => {
+                if args.is_empty() {
+                    return Err(format!("{}: expected a format string", func_name));
+                }
+                let fmt = to_str(func_name, &args[0])?;
+                let formatted = format_string(func_name, fmt, &args[1..])?;
+                if func_name == "format" {
+                    return Ok(VarType::String(formatted));
+                }
+                println!("{}", formatted);
+                Ok(VarType::Null)
+            }
+            "to_json" => {
+                check_arg_count(func_name, args, 1)?;
+                Ok(VarType::String(value_to_json_pretty(&args[0], 0)))
+            }
+            "parse_json" => {
+                check_arg_count(func_name, args, 1)?;
+                parse_json(to_str(func_name, &args[0])?)
+            }
+            "env" => {
+                // 未設定なら二つ目の引数かNullを返す
+                if args.is_empty() || args.len() > 2 {
+                    return Err(format!(
+                        "env: expected 1 or 2 argument(s) but got {}",
+                        args.len()
+                    ));
+                }
+                match std::env::var(to_str(func_name, &args[0])?) {
+                    Ok(value) => Ok(VarType::String(value)),
+                    Err(_) => Ok(args.get(1).cloned().unwrap_or(VarType::Null)),
+                }
+            }
+            "time_now" | "time_now_millis" => {
+                check_arg_count(func_name, args, 0)?;
+                let elapsed = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|e| format!("{}: {}", func_name, e))?;
+                let time = if func_name == "time_now" {
+                    elapsed.as_secs() as i64
+                } else {
+                    elapsed.as_millis() as i64
+                };
+                Ok(VarType::Int(time))
+            }
+            "random" => {
+                check_arg_count(func_name, args, 0)?;
+                // 上位53ビットを[0, 1)の浮動小数点数にする
+                Ok(VarType::Float(
+                    (self.next_random() >> 11) as f64 / (1u64 << 53) as f64,
+                ))
+            }
+            "random_int" => {
+                check_arg_count(func_name, args, 2)?;
+                let (min, max) = (to_i64(func_name, &args[0])?, to_i64(func_name, &args[1])?);
+                if min > max {
+                    return Err(format!(
+                        "random_int: min {} is greater than max {}",
+                        min, max
+                    ));
+                }
+                let width = (max as i128 - min as i128 + 1) as u128;
+                let offset = (self.next_random() as u128 % width) as i128;
+                Ok(VarType::Int((min as i128 + offset) as i64))
+            }
+            "assert" => {
+                check_arg_count(func_name, args, 1)?;
+                if to_bool(func_name, &args[0])? {
+                    Ok(VarType::Null)
+                } else {
+                    Err("assertion failed".to_string())
+                }
+            }
+            "assert_eq" => {
+                check_arg_count(func_name, args, 2)?;
+                if args[0] == args[1] {
+                    Ok(VarType::Null)
+                } else {
+                    Err(format!(
+                        "assertion failed: {} != {}",
+                        value_to_json(&args[0]),
+                        value_to_json(&args[1])
+                    ))
+                }
+            }
+            "type_of" => {
+                check_arg_count(func_name, args, 1)?;
+                Ok(VarType::String(infer_type(&args[0]).to_string()))
+            }
+            "exit" => {
+                if args.len() > 1 {
+                    return Err(format!(
+                        "exit: expected 0 or 1 argument(s) but got {}",
+                        args.len()
+                    ));
+                }
+                let code = match args.first() {
+                    Some(code) => to_i64(func_name, code)?,
+                    None => 0,
+                };
+                let code = i32::try_from(code)
+                    .map_err(|_| format!("exit: code out of range: {}", code))?;
+                // 実行を止めるためにエラーで戻り、decodeとeval_lineはexit_codeで見分ける
+                self.exit_code = Some(code);
+                Err(format!("exit({})", code))
+            }
+            _ => Err(format!("Unknown function: {}", func_name)),
+        }
     }
 }
 // ファイルから内容を取得
@@ -327,19 +2658,100 @@ pub fn read_file(file_name: &str) -> Result<String, Error> {
     Ok(contents)
 }
 
+// エラーがあっても次の;か}まで読み飛ばして続け、すべてのエラーを返す
+pub fn program_all(parser: &mut Parser) -> (Vec<Box<Node>>, Vec<String>) {
+    let mut nodes = Vec::new();
+    let mut errors = Vec::new();
+    while parser.current_tokens().token_type != TokenType::Eof {
+        match parser.statement() {
+            Ok(node) => nodes.push(node),
+            Err(e) => {
+                let token = parser.current_tokens();
+                errors.push(format!(
+                    "{} (line {}, column {})",
+                    e, token.line, token.column
+                ));
+                while !matches!(
+                    parser.current_tokens().token_type,
+                    TokenType::Semi | TokenType::RBlockDelimiter | TokenType::Eof
+                ) {
+                    parser.next_tokens();
+                }
+                if parser.current_tokens().token_type == TokenType::RBlockDelimiter {
+                    parser.next_tokens();
+                }
+            }
+        }
+        if parser.current_tokens().token_type == TokenType::Semi {
+            parser.next_tokens();
+        }
+    }
+    (nodes, errors)
+}
+
+// 一度だけリテラルで宣言され書き換えられない変数を、宣言より後の読み込み箇所に埋め込む
+pub fn propagate_constants(program: &[Box<Node>]) -> Vec<Box<Node>> {
+    let mut declared = HashMap::new();
+    let mut mutated = HashSet::new();
+    for node in program {
+        node.count_bindings(&mut declared, &mut mutated);
+    }
+    let mut constants = HashMap::new();
+    let mut optimized = Vec::new();
+    for node in program {
+        let mut node = node.clone();
+        node.inline_constants(&constants);
+        if matches!(node.node_type, NodeType::VarDecl | NodeType::ConstDecl)
+            && declared.get(&node.value) == Some(&1)
+            && !mutated.contains(&node.value)
+            && matches!(
+                node.child[0].node_type,
+                NodeType::Num(_) | NodeType::Str(_) | NodeType::Bool(_) | NodeType::Char(_)
+            )
+        {
+            constants.insert(node.value.clone(), node.child[0].clone());
+        }
+        optimized.push(node);
+    }
+    optimized
+}
+
+// 一度も読まれない変数の警告を宣言の位置付きで返す (_で始まる名前は除く)
+pub fn unused_variables(program: &[Box<Node>]) -> Vec<String> {
+    let mut declared = Vec::new();
+    let mut read = HashSet::new();
+    for node in program {
+        node.collect_variables(&mut declared, &mut read);
+    }
+    declared
+        .iter()
+        .filter(|decl| !decl.value.starts_with('_') && !read.contains(&decl.value))
+        .map(|decl| {
+            format!(
+                "unused variable: {} (line {}, column {})",
+                decl.value, decl.line, decl.column
+            )
+        })
+        .collect()
+}
+
 // トークン化データからプログラムノードのリストを返す
 pub fn program(parser: &mut Parser) -> Result<Vec<Box<Node>>, String> {
     let mut nodes: Vec<Box<Node>> = Vec::new();
 
     // トークン列をすべて処理する
     loop {
-        let node = parser.expr()?;
-        nodes.push(node);
-
         // 次のトークンがEOFかどうかチェック
         if parser.current_tokens().token_type == TokenType::Eof {
             break;
         }
+        let node = parser.statement()?;
+        nodes.push(node);
+
+        // 文末の;は省略できる
+        if parser.current_tokens().token_type == TokenType::Semi {
+            parser.next_tokens();
+        }
     }
     Ok(nodes)
 }
@@ -1,51 +1,201 @@
 use crate::types::*;
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
     pub value: String,
+    pub line: usize,   // 1始まりの行番号
+    pub column: usize, // 1始まりの列番号
 }
 impl Token {
     fn new(token_type: TokenType, value: String) -> Token {
-        Token { token_type, value }
+        Token {
+            token_type,
+            value,
+            line: 0,
+            column: 0,
+        }
     }
 }
 pub struct Tokenizer {}
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Tokenizer {
     pub fn new() -> Tokenizer {
         Tokenizer {}
     }
-    pub fn tokenize(&self, input: &String) -> Result<Vec<Token>, String> {
+    pub fn tokenize(&self, input: &str) -> Result<Vec<Token>, String> {
+        // 先頭のBOMを取り除き、改行コードをLFにそろえる
+        let normalized = input
+            .strip_prefix('\u{feff}')
+            .unwrap_or(input)
+            .replace("\r\n", "\n")
+            .replace('\r', "\n");
+        // マルチバイト文字を扱えるように文字単位で読む
+        let chars: Vec<char> = normalized.chars().collect();
         let mut tokens: Vec<Token> = Vec::new();
         let mut pos = 0;
-        while pos < input.len() {
-            let mut c = input.chars().nth(pos).expect("Index out of bounds");
+        // トークンの開始位置の行と列
+        let (mut line, mut column, mut scanned) = (1, 1, 0);
+        while pos < chars.len() {
+            while scanned < pos {
+                if chars[scanned] == '\n' {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+                scanned += 1;
+            }
+            let first_new_token = tokens.len();
+            let mut c = chars[pos];
             if c == ' ' || c == '\n' {
                 pos += 1;
                 continue;
-            } else if c.is_digit(10) {
+            } else if c == '/' && chars.get(pos + 1) == Some(&'*') {
+                // ブロックコメントは対応する*/まで読み飛ばす (入れ子にできる)
+                pos += 2;
+                let mut depth = 1;
+                loop {
+                    if pos + 1 >= chars.len() {
+                        return Err(format!(
+                            "ブロックコメントが閉じられていませんよ {}行 {}列",
+                            line, column
+                        ));
+                    }
+                    if chars[pos] == '/' && chars[pos + 1] == '*' {
+                        depth += 1;
+                        pos += 2;
+                    } else if chars[pos] == '*' && chars[pos + 1] == '/' {
+                        depth -= 1;
+                        pos += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        pos += 1;
+                    }
+                }
+                continue;
+            } else if c.is_ascii_digit() {
                 let mut num = String::new();
-                while pos < input.len() && c.is_digit(10) {
+                let mut is_float = false;
+                // 小数点は一つだけ数値の一部として読む
+                while pos < chars.len() && (c.is_ascii_digit() || (c == '.' && !is_float)) {
+                    if c == '.' {
+                        is_float = true;
+                    }
                     num.push(c);
                     pos += 1;
-                    if pos < input.len() {
-                        c = input.chars().nth(pos).expect("Index out of bounds");
+                    if pos < chars.len() {
+                        c = chars[pos];
+                    }
+                }
+                // 指数部 (e/Eの後に符号付きの数字) があれば浮動小数点数として読む
+                if pos < chars.len() && (c == 'e' || c == 'E') {
+                    let mut exp_end = pos + 1;
+                    if exp_end < chars.len() && (chars[exp_end] == '+' || chars[exp_end] == '-') {
+                        exp_end += 1;
+                    }
+                    if exp_end < chars.len() && chars[exp_end].is_ascii_digit() {
+                        while exp_end < chars.len() && chars[exp_end].is_ascii_digit() {
+                            exp_end += 1;
+                        }
+                        num.extend(&chars[pos..exp_end]);
+                        pos = exp_end;
+                        is_float = true;
+                    }
+                }
+                if is_float {
+                    tokens.push(Token::new(TokenType::Float, num));
+                } else {
+                    tokens.push(Token::new(TokenType::Int, num));
+                }
+            } else if c == '"' {
+                // 閉じる"までを文字列として読む
+                let mut string = String::new();
+                pos += 1;
+                loop {
+                    if pos >= chars.len() {
+                        return Err("文字列が閉じられていませんよ".to_string());
+                    }
+                    c = chars[pos];
+                    pos += 1;
+                    if c == '"' {
+                        break;
                     }
+                    if c == '\\' {
+                        string.push(read_escape(&chars, &mut pos)?);
+                        continue;
+                    }
+                    if c == '$' && chars.get(pos) == Some(&'{') {
+                        // ${式} の中身は対応する}までをそのまま取り出す
+                        tokens.push(Token::new(TokenType::String, std::mem::take(&mut string)));
+                        let end = interpolation_end(&chars, pos + 1)
+                            .ok_or_else(|| "${が閉じられていませんよ".to_string())?;
+                        let source = chars[pos + 1..end].iter().collect();
+                        pos = end + 1;
+                        tokens.push(Token::new(TokenType::Interpolation, source));
+                        continue;
+                    }
+                    string.push(c);
+                }
+                tokens.push(Token::new(TokenType::String, string));
+            } else if c == '\'' {
+                // 'の間の一文字を文字リテラルとして読む
+                pos += 1;
+                if pos >= chars.len() || chars[pos] == '\'' {
+                    return Err("文字リテラルが空ですよ".to_string());
+                }
+                let ch = if chars[pos] == '\\' {
+                    pos += 1;
+                    read_escape(&chars, &mut pos)?
+                } else {
+                    pos += 1;
+                    chars[pos - 1]
+                };
+                if pos >= chars.len() || chars[pos] != '\'' {
+                    return Err("文字リテラルには一文字だけ書いてくださいね".to_string());
                 }
-                tokens.push(Token::new(TokenType::Int, num));
-            } else if c.is_alphabetic() {
+                pos += 1;
+                tokens.push(Token::new(TokenType::Char, ch.to_string()));
+            } else if c.is_alphabetic() || c == '_' {
                 let mut ident = String::new();
-                while pos < input.len() && c.is_alphabetic() {
+                while pos < chars.len() && (c.is_alphanumeric() || c == '_') {
                     ident.push(c);
                     pos += 1;
-                    if pos < input.len() {
-                        c = input.chars().nth(pos).expect("Index out of bounds");
+                    if pos < chars.len() {
+                        c = chars[pos];
                     }
                 }
                 if ident == "let" || ident == "l" {
                     tokens.push(Token::new(TokenType::LetDecl, ident));
+                } else if ident == "const" {
+                    tokens.push(Token::new(TokenType::ConstDecl, ident));
+                } else if ident == "global" {
+                    tokens.push(Token::new(TokenType::Global, ident));
+                } else if ident == "fn" {
+                    tokens.push(Token::new(TokenType::Fn, ident));
+                } else if ident == "enum" {
+                    tokens.push(Token::new(TokenType::Enum, ident));
+                } else if ident == "match" {
+                    tokens.push(Token::new(TokenType::Match, ident));
+                } else if ident == "and" {
+                    tokens.push(Token::new(TokenType::And, ident));
+                } else if ident == "or" {
+                    tokens.push(Token::new(TokenType::Or, ident));
+                } else if ident == "not" {
+                    tokens.push(Token::new(TokenType::Not, ident));
+                } else if ident == "true" || ident == "false" {
+                    tokens.push(Token::new(TokenType::Bool, ident));
                 } else {
                     tokens.push(Token::new(TokenType::Ident, ident));
                 }
+            } else if let Some(token_type) = two_char_operator(c, chars.get(pos + 1)) {
+                tokens.push(Token::new(token_type, chars[pos..pos + 2].iter().collect()));
+                pos += 2;
             } else {
                 match c {
                     ';' => tokens.push(Token::new(TokenType::Semi, ";".to_string())),
@@ -54,8 +204,20 @@ impl Tokenizer {
                     '-' => tokens.push(Token::new(TokenType::Sub, "-".to_string())),
                     '*' => tokens.push(Token::new(TokenType::Mul, "*".to_string())),
                     '/' => tokens.push(Token::new(TokenType::Div, "/".to_string())),
+                    '%' => tokens.push(Token::new(TokenType::Mod, "%".to_string())),
+                    '<' => tokens.push(Token::new(TokenType::Lt, "<".to_string())),
+                    '>' => tokens.push(Token::new(TokenType::Gt, ">".to_string())),
+                    '!' => tokens.push(Token::new(TokenType::Not, "!".to_string())),
                     '(' => tokens.push(Token::new(TokenType::LParen, "(".to_string())),
                     ')' => tokens.push(Token::new(TokenType::RParen, ")".to_string())),
+                    '{' => tokens.push(Token::new(TokenType::LBlockDelimiter, "{".to_string())),
+                    '}' => tokens.push(Token::new(TokenType::RBlockDelimiter, "}".to_string())),
+                    ',' => tokens.push(Token::new(TokenType::Comma, ",".to_string())),
+                    ':' => tokens.push(Token::new(TokenType::Colon, ":".to_string())),
+                    '.' => tokens.push(Token::new(TokenType::Dot, ".".to_string())),
+                    '[' => tokens.push(Token::new(TokenType::LBracket, "[".to_string())),
+                    ']' => tokens.push(Token::new(TokenType::RBracket, "]".to_string())),
+                    '@' => tokens.push(Token::new(TokenType::At, "@".to_string())),
                     _ => {
                         tokens.push(Token::new(TokenType::Error, "Error!".to_string()));
                         return Err(format!("この文字はトークンではありませんよ {}", c));
@@ -63,8 +225,124 @@ impl Tokenizer {
                 }
                 pos += 1;
             }
+            for token in &mut tokens[first_new_token..] {
+                token.line = line;
+                token.column = column;
+            }
         }
-        tokens.push(Token::new(TokenType::Eof, "".to_string()));
+        let mut eof = Token::new(TokenType::Eof, "".to_string());
+        (eof.line, eof.column) = position(&chars, chars.len());
+        tokens.push(eof);
         Ok(tokens)
     }
 }
+
+// 二文字の演算子を判定する
+fn two_char_operator(c: char, next: Option<&char>) -> Option<TokenType> {
+    match (c, next?) {
+        ('=', '>') => Some(TokenType::FatArrow),
+        (':', ':') => Some(TokenType::PathSep),
+        ('=', '=') => Some(TokenType::Eq),
+        ('!', '=') => Some(TokenType::Ne),
+        ('<', '=') => Some(TokenType::Le),
+        ('>', '=') => Some(TokenType::Ge),
+        ('&', '&') => Some(TokenType::And),
+        ('|', '|') => Some(TokenType::Or),
+        ('*', '*') => Some(TokenType::Pow),
+        ('+', '+') => Some(TokenType::Increment),
+        ('-', '-') => Some(TokenType::Decrement),
+        _ => None,
+    }
+}
+
+// ${の直後から対応する}の位置を探す (中の文字列や文字のリテラルは読み飛ばす)
+fn interpolation_end(chars: &[char], mut pos: usize) -> Option<usize> {
+    let mut depth = 0;
+    loop {
+        match *chars.get(pos)? {
+            '}' if depth == 0 => return Some(pos),
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            quote @ ('"' | '\'') => pos = literal_end(chars, pos + 1, quote)?,
+            _ => {}
+        }
+        pos += 1;
+    }
+}
+
+// 開く引用符の直後から閉じる引用符の位置を探す
+fn literal_end(chars: &[char], mut pos: usize, quote: char) -> Option<usize> {
+    loop {
+        match *chars.get(pos)? {
+            '\\' => pos += 1,
+            '$' if quote == '"' && chars.get(pos + 1) == Some(&'{') => {
+                pos = interpolation_end(chars, pos + 2)?;
+            }
+            c if c == quote => return Some(pos),
+            _ => {}
+        }
+        pos += 1;
+    }
+}
+
+// \の後のエスケープシーケンスを読み、posをその直後まで進める
+fn read_escape(chars: &[char], pos: &mut usize) -> Result<char, String> {
+    let start = *pos;
+    let c = match chars.get(start) {
+        Some(c) => *c,
+        None => return Err("エスケープシーケンスが途中で終わっていますよ".to_string()),
+    };
+    *pos += 1;
+    match c {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        'r' => Ok('\r'),
+        '0' => Ok('\0'),
+        '\\' => Ok('\\'),
+        '\'' => Ok('\''),
+        '"' => Ok('"'),
+        '$' => Ok('$'),
+        'u' => {
+            // \u{XXXX} 形式のUnicodeコードポイント
+            if chars.get(*pos) != Some(&'{') {
+                return Err(escape_error(chars, start));
+            }
+            let mut hex = String::new();
+            *pos += 1;
+            while let Some(&h) = chars.get(*pos) {
+                *pos += 1;
+                if h == '}' {
+                    return u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| escape_error(chars, start));
+                }
+                hex.push(h);
+            }
+            Err(escape_error(chars, start))
+        }
+        _ => Err(escape_error(chars, start)),
+    }
+}
+
+// 不正なエスケープシーケンスの位置を行と列で示す
+fn escape_error(chars: &[char], pos: usize) -> String {
+    // \の位置を示す
+    let (line, column) = position(chars, pos - 1);
+    format!(
+        "不明なエスケープシーケンスですよ {}行 {}列: \\{}",
+        line,
+        column,
+        chars.get(pos).copied().unwrap_or(' ')
+    )
+}
+
+// 文字の位置から1始まりの行と列を求める
+fn position(chars: &[char], pos: usize) -> (usize, usize) {
+    let line = chars[..pos].iter().filter(|c| **c == '\n').count() + 1;
+    let line_start = chars[..pos]
+        .iter()
+        .rposition(|c| *c == '\n')
+        .map_or(0, |i| i + 1);
+    (line, pos - line_start + 1)
+}
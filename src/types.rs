@@ -4,36 +4,200 @@ pub enum TokenType {
     RBlockDelimiter, // }
     Char,            // ''
     String,          // ""
+    Interpolation,   // 文字列中の${式}
     Int,             // 整数値
+    Float,           // 浮動小数点数
+    Bool,            // true / false
     LetDecl,         // let宣言
+    ConstDecl,       // const宣言
+    Global,          // global
+    Match,           // match
+    Fn,              // fn
+    Enum,            // enum
     Ident,           // 識別子
     Add,             // +
     Sub,             // -
     Mul,             // *
     Div,             // /
+    Mod,             // %
+    Pow,             // **
+    Increment,       // ++
+    Decrement,       // --
+    Eq,              // ==
+    Ne,              // !=
+    Lt,              // <
+    Gt,              // >
+    Le,              // <=
+    Ge,              // >=
+    And,             // &&
+    Or,              // ||
+    Not,             // !
     LParen,          // (
     RParen,          // )
+    Comma,           // ,
+    Colon,           // :
+    PathSep,         // ::
+    Dot,             // .
+    LBracket,        // [
+    RBracket,        // ]
+    At,              // @ (システム関数呼び出し)
     Assign,          // =
+    FatArrow,        // =>
     Semi,            // ;
     Eof,             // トークンの終わり
     Error,           // エラー時
 }
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum NodeType {
-    Var(String), // 変数
-    VarDecl,     // 変数宣言
-    VarAssign,   // 変数代入
-    Add,         // +
-    Sub,         // -
-    Mul,         // *
-    Div,         // /
-    Num(String), // 値
-    Error,       // エラー
-}
-#[derive(Clone, Debug)]
+    Var(String),           // 変数
+    VarDecl,               // 変数宣言
+    ConstDecl,             // 定数宣言
+    MultiDecl,             // let a, b = 1, 2
+    Global,                // global let x = 1
+    VarAssign,             // 変数代入
+    Add,                   // +
+    Sub,                   // -
+    Mul,                   // *
+    Div,                   // /
+    Mod,                   // %
+    Pow,                   // **
+    Neg,                   // 単項 -
+    Not,                   // !
+    Eq,                    // ==
+    Ne,                    // !=
+    Lt,                    // <
+    Gt,                    // >
+    Le,                    // <=
+    Ge,                    // >=
+    And,                   // &&
+    Or,                    // ||
+    PostInc,               // 後置 ++
+    PostDec,               // 後置 --
+    Num(String),           // 値
+    Bool(bool),            // 真偽値
+    Str(String),           // 文字列
+    Char(char),            // 文字
+    Call(String),          // システム関数呼び出し
+    Interpolation,         // 文字列中の${式} (変数に隠されない@str)
+    Block(bool),           // ブロック (末尾の式を値として返すか)
+    Match,                 // match式
+    Wildcard,              // matchの_
+    Map,                   // マップリテラル
+    Array,                 // 配列リテラル
+    Tuple,                 // タプルリテラル
+    TupleField,            // タプルの.0, .1 ...
+    Index,                 // 添字による読み込み
+    IndexAssign,           // 添字による代入
+    TupleAssign,           // (a, b) = 式
+    Lambda(Vec<String>),   // fn(引数) => 式
+    EnumDecl(Vec<String>), // enum 名前 { 列挙子, ... }
+    EnumVariant(String),   // 列挙型::列挙子
+    Error,                 // エラー
+}
+use crate::parser::Node;
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[derive(Clone, Debug, PartialEq)]
 pub enum VarType {
     Int(i64),
     String(String),
     Bool(bool),
     Float(f64),
+    Array(Vec<VarType>),
+    Object(BTreeMap<String, VarType>),
+    Tuple(Vec<VarType>),
+    Lambda(Box<Closure>),
+    Enum(String, String), // 列挙型の名前と列挙子
+    Null,
+}
+
+// fn(引数) => 式 の値 (作られたときに見えていた変数を複製して持つ)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Closure {
+    pub params: Vec<String>,
+    pub body: Node,
+    pub captured: HashMap<String, VarType>,
+    pub constants: HashSet<String>, // capturedのうち定数の名前
+}
+
+// Rustの値とスクリプトの値の変換
+impl From<i64> for VarType {
+    fn from(value: i64) -> Self {
+        VarType::Int(value)
+    }
+}
+impl From<f64> for VarType {
+    fn from(value: f64) -> Self {
+        VarType::Float(value)
+    }
+}
+impl From<bool> for VarType {
+    fn from(value: bool) -> Self {
+        VarType::Bool(value)
+    }
+}
+impl From<String> for VarType {
+    fn from(value: String) -> Self {
+        VarType::String(value)
+    }
+}
+impl From<&str> for VarType {
+    fn from(value: &str) -> Self {
+        VarType::String(value.to_string())
+    }
+}
+impl<T: Into<VarType>> From<Vec<T>> for VarType {
+    fn from(values: Vec<T>) -> Self {
+        VarType::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+impl TryFrom<VarType> for i64 {
+    type Error = String;
+
+    fn try_from(value: VarType) -> Result<Self, Self::Error> {
+        match value {
+            VarType::Int(v) => Ok(v),
+            other => Err(format!("Expected an integer but found {:?}", other)),
+        }
+    }
+}
+impl TryFrom<VarType> for f64 {
+    type Error = String;
+
+    fn try_from(value: VarType) -> Result<Self, Self::Error> {
+        match value {
+            VarType::Float(v) => Ok(v),
+            VarType::Int(v) => Ok(v as f64),
+            other => Err(format!("Expected a number but found {:?}", other)),
+        }
+    }
+}
+impl TryFrom<VarType> for bool {
+    type Error = String;
+
+    fn try_from(value: VarType) -> Result<Self, Self::Error> {
+        match value {
+            VarType::Bool(v) => Ok(v),
+            other => Err(format!("Expected a bool but found {:?}", other)),
+        }
+    }
+}
+impl TryFrom<VarType> for String {
+    type Error = String;
+
+    fn try_from(value: VarType) -> Result<Self, Self::Error> {
+        match value {
+            VarType::String(v) => Ok(v),
+            other => Err(format!("Expected a string but found {:?}", other)),
+        }
+    }
+}
+impl<T: TryFrom<VarType, Error = String>> TryFrom<VarType> for Vec<T> {
+    type Error = String;
+
+    fn try_from(value: VarType) -> Result<Self, Self::Error> {
+        match value {
+            VarType::Array(values) => values.into_iter().map(T::try_from).collect(),
+            other => Err(format!("Expected an array but found {:?}", other)),
+        }
+    }
 }